@@ -1,32 +1,109 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use md5;
-use regex::Regex;
+use flate2::read::GzDecoder;
+use indicatif::{DecimalBytes, HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use reqwest::header::{HeaderValue, HeaderMap};
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_xml_rs::from_str;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgGroup, ArgMatches};
 use std::error::Error;
 use std::fs;
-use std::io::{Seek, Write};
+use std::io::{Read, Seek, Write};
 use std::process;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Set when stdout carries machine readable output such as JSON.
+static MACHINE_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Set when errors are reported as JSON objects on stderr.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Set when sizes are shown and parsed in 1000 based SI units rather than 1024 based IEC units.
+static SI_UNITS: AtomicBool = AtomicBool::new(false);
+
+/// Set when progress is shown as a single plain line instead of status messages.
+static SIMPLE_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Set when only the final reports and errors are shown, without progress.
+static SUMMARY_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Shared by the progress bars of items downloaded in parallel with
+/// `--parallel-items`, so that each bar keeps a line of its own.
+static PARALLEL_PROGRESS: std::sync::OnceLock<indicatif::MultiProgress> = std::sync::OnceLock::new();
+
+tokio::task_local! {
+    /// Starts every line printed for an item downloaded in parallel with others.
+    static ITEM_PREFIX: String;
+}
+
+/// Print a line of a final report, moved to stderr when stdout carries JSON.
+macro_rules! summary {
+    ($($arg:tt)*) => {
+        print_line(format_args!($($arg)*))
+    };
+}
+
+/// Print human readable status, like `summary!` but hidden by `--simple-progress`
+/// and `--summary-only`.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if status_shown() {
+            summary!($($arg)*);
+        }
+    };
+}
+
+/// Print `line` for `summary!`. A `--simple-progress` line is ended first so the
+/// line starts on its own, and the bars of parallel items are cleared while it prints.
+fn print_line(line: std::fmt::Arguments) {
+    finish_simple_line();
+    let prefix = ITEM_PREFIX.try_with(String::clone).unwrap_or_default();
+    let print = || {
+        if MACHINE_OUTPUT.load(Ordering::Relaxed) {
+            eprintln!("{}{}", prefix, line);
+        } else {
+            println!("{}{}", prefix, line);
+        }
+    };
+    match PARALLEL_PROGRESS.get() {
+        Some(bars) => bars.suspend(print),
+        None => print(),
+    }
+}
+
+/// Print with `pb` cleared from the terminal. The bars of parallel items are
+/// already cleared by `summary!`, which must not be nested in their lock.
+fn suspend_progress(pb: &ProgressBar, print: impl FnOnce()) {
+    match PARALLEL_PROGRESS.get() {
+        Some(_) => print(),
+        None => pb.suspend(print),
+    }
+}
+
+/// Whether status messages are shown, only the final reports are with
+/// `--simple-progress` or `--summary-only`.
+fn status_shown() -> bool {
+    !SIMPLE_PROGRESS.load(Ordering::Relaxed) && !SUMMARY_ONLY.load(Ordering::Relaxed)
+}
 
 #[derive(Deserialize, Debug)]
 struct XmlFiles {
-    #[serde(rename = "file")]
+    #[serde(rename = "file", default)]
     files: Vec<XmlFile>,
 }
 
 #[allow(dead_code)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 struct XmlFile {
     #[serde(rename = "name")]
     name: String,
     #[serde(rename = "source")]
     source: String,
+    /// Modification time in Unix seconds, kept as text so one malformed value
+    /// does not stop the whole listing from parsing, see `parse_mtime`.
     #[serde(rename = "mtime")]
-    mtime: Option<u64>,
+    mtime: Option<String>,
     #[serde(rename = "size")]
     size: Option<u64>,
     #[serde(rename = "format")]
@@ -49,193 +126,6101 @@ struct XmlFile {
     old_version: Option<bool>,
 }
 
-async fn is_url_accessible(url: &str) -> Result<bool, Box<dyn Error>> {
-    let client = reqwest::Client::new();
-    let res = client.get(url).send().await;
+/// Archive.org format names for common extensions, used for files listed without a format.
+const EXTENSION_FORMATS: &[(&str, &str)] = &[
+    ("pdf", "Text PDF"),
+    ("epub", "EPUB"),
+    ("djvu", "DjVu"),
+    ("txt", "Text"),
+    ("html", "HTML"),
+    ("htm", "HTML"),
+    ("jpg", "JPEG"),
+    ("jpeg", "JPEG"),
+    ("png", "PNG"),
+    ("gif", "Animated GIF"),
+    ("tif", "TIFF"),
+    ("tiff", "TIFF"),
+    ("jp2", "JPEG 2000"),
+    ("mp3", "VBR MP3"),
+    ("flac", "Flac"),
+    ("ogg", "Ogg Vorbis"),
+    ("wav", "WAVE"),
+    ("mp4", "MPEG4"),
+    ("mkv", "Matroska"),
+    ("avi", "AVI"),
+    ("webm", "WebM"),
+    ("srt", "SubRip"),
+    ("zip", "ZIP"),
+    ("gz", "GZIP"),
+    ("tar", "TAR"),
+    ("iso", "ISO Image"),
+    ("torrent", "Archive BitTorrent"),
+];
 
-    match res {
-        Ok(response) => {
-            if response.status().is_success() {
-                Ok(true)
-            } else {
-                Ok(false)
+impl XmlFile {
+    /// The modification time from the metadata in Unix seconds, if it is valid.
+    fn mtime_seconds(&self) -> Option<u64> {
+        self.mtime.as_deref().and_then(|mtime| parse_mtime(mtime).ok())
+    }
+
+    /// The file's format from the metadata, or one inferred from its extension
+    /// when the metadata has none.
+    fn format_or_inferred(&self) -> Option<&str> {
+        self.format.as_deref().or_else(|| {
+            let (_, extension) = self.name.rsplit_once('.')?;
+            EXTENSION_FORMATS
+                .iter()
+                .find(|(known, _)| extension.eq_ignore_ascii_case(known))
+                .map(|(_, format)| *format)
+        })
+    }
+}
+
+/// Item level metadata from the archive.org metadata API.
+#[derive(Deserialize, Debug, Default)]
+struct ItemMetadata {
+    #[serde(default)]
+    metadata: ItemFields,
+    /// Mirrors that currently hold a copy of the item.
+    #[serde(default)]
+    workable_servers: Vec<String>,
+    /// Path of the item on its mirrors.
+    dir: Option<String>,
+}
+
+/// Descriptive fields of an item. Archive.org stores most of them as either
+/// a single string or a list, so each one is read into a list of strings.
+#[derive(Deserialize, Debug, Default)]
+struct ItemFields {
+    #[serde(default, deserialize_with = "string_or_vec")]
+    title: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_vec")]
+    creator: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_vec")]
+    date: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_vec")]
+    description: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_vec")]
+    collection: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_vec")]
+    subject: Vec<String>,
+}
+
+/// A metadata value that may be a single value or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrVec {
+    Many(Vec<serde_json::Value>),
+    One(serde_json::Value),
+}
+
+/// Deserialize a string, number or list of them into a list of strings,
+/// skipping nulls and anything nested.
+fn string_or_vec<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let values = match StringOrVec::deserialize(deserializer)? {
+        StringOrVec::Many(values) => values,
+        StringOrVec::One(value) => vec![value],
+    };
+    Ok(values
+        .into_iter()
+        .filter_map(|value| match value {
+            serde_json::Value::String(value) => Some(value),
+            serde_json::Value::Number(value) => Some(value.to_string()),
+            serde_json::Value::Bool(value) => Some(value.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Fetch the raw item metadata document, use `serde_json::from_value` to pick out fields.
+async fn fetch_item_metadata(client: &Client, identifier: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+    let url = format!("https://archive.org/metadata/{}", identifier);
+    let response = check_response(client.get(&url).send().await?)?;
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+/// The collections an item belongs to, from its `collection` metadata.
+async fn item_collections(client: &Client, item: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let identifier = normalize_archive_identifier(item)?;
+    let item: ItemMetadata = serde_json::from_value(fetch_item_metadata(client, &identifier).await?)?;
+    Ok(item.metadata.collection)
+}
+
+/// The restriction recorded in an item's metadata, if it is dark or access restricted.
+fn item_restriction(raw_metadata: &serde_json::Value) -> Option<IaGetError> {
+    let flag = |value: &serde_json::Value| value.as_bool() == Some(true) || value.as_str() == Some("true");
+    if flag(&raw_metadata["is_dark"]) {
+        Some(IaGetError::Restricted { dark: true })
+    } else if flag(&raw_metadata["metadata"]["access-restricted-item"]) {
+        Some(IaGetError::Restricted { dark: false })
+    } else {
+        None
+    }
+}
+
+/// Explain a 401 or 403 for an item: a restriction from its metadata when it
+/// has one, otherwise `error` unchanged.
+async fn explain_forbidden(client: &Client, identifier: &str, error: Box<dyn Error>) -> Box<dyn Error> {
+    if !matches!(error.downcast_ref::<IaGetError>(), Some(IaGetError::Forbidden { .. })) {
+        return error;
+    }
+    match fetch_item_metadata(client, identifier).await {
+        Ok(raw) => item_restriction(&raw).map_or(error, |restriction| restriction.into()),
+        Err(_) => error,
+    }
+}
+
+/// The item's download directory on `server`, which must be one of the item's
+/// workable servers unless `force` is set.
+fn mirror_item_url(server: &str, raw_metadata: Option<&serde_json::Value>, force: bool) -> Result<reqwest::Url, Box<dyn Error>> {
+    let item: ItemMetadata = match raw_metadata {
+        Some(raw) => serde_json::from_value(raw.clone())?,
+        None => ItemMetadata::default(),
+    };
+    let dir = item.dir.ok_or("The item metadata has no dir to build mirror URLs from")?;
+    if !force && !item.workable_servers.iter().any(|workable| workable == server) {
+        return Err(format!(
+            "{} is not a workable server for this item, choose one of {} or use --force-server",
+            server,
+            item.workable_servers.join(", ")
+        )
+        .into());
+    }
+    Ok(reqwest::Url::parse(&format!("https://{}{}", server, dir))?)
+}
+
+/// Longest description written to `README.txt`, in characters.
+const MAX_README_DESCRIPTION: usize = 2000;
+
+/// Reduce an HTML description to plain text on a single line, truncated to `max` characters.
+fn plain_description(html: &str, max: usize) -> String {
+    let mut text = String::new();
+    let mut tag: Option<String> = None;
+    for c in html.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (Some(name), '>') => {
+                // Block level tags separate words, inline tags such as <b> do not
+                let name = name.trim_start_matches('/').to_ascii_lowercase();
+                if ["br", "p", "div", "li", "tr", "h"].iter().any(|block| name.starts_with(block)) {
+                    text.push(' ');
+                }
+                tag = None;
             }
-        },
-        Err(_) => Ok(false),
+            (Some(name), c) => name.push(c),
+            (None, c) => text.push(c),
+        }
+    }
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() > max {
+        format!("{}...", text.chars().take(max).collect::<String>().trim_end())
+    } else {
+        text
     }
 }
 
-fn get_xml_url(original_url: &str) -> String {
-    let base_new_url = original_url.replacen("details", "download", 1);
-    if let Some(last_segment) = original_url.split('/').last() {
-        format!("{}/{}_files.xml", base_new_url, last_segment)
+/// Write `ia-metadata.json` with the raw item metadata and a human readable
+/// `README.txt` summary into the item's output directory, returning the path
+/// of the metadata file. Each is renamed by [`sidecar_name`] when the item has
+/// a file of the same name.
+fn write_metadata_sidecar(output_dir: &Path, identifier: &str, raw: &serde_json::Value, files: &[XmlFile]) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(output_dir)?;
+    let metadata_path = output_dir.join(sidecar_name("ia-metadata.json", identifier, files));
+    fs::write(&metadata_path, serde_json::to_string_pretty(raw)?)?;
+
+    let item: ItemMetadata = serde_json::from_value(raw.clone())?;
+    let fields = &item.metadata;
+    let mut readme = format!("{}\n\n", fields.title.join(" / "));
+    readme.push_str(&format!("Identifier:  {}\n", identifier));
+    readme.push_str(&format!("URL:         https://archive.org/details/{}\n", identifier));
+    readme.push_str(&format!("Creator:     {}\n", fields.creator.join("; ")));
+    readme.push_str(&format!("Date:        {}\n", fields.date.first().map(String::as_str).unwrap_or_default()));
+    if !fields.subject.is_empty() {
+        readme.push_str(&format!("Subjects:    {}\n", fields.subject.join("; ")));
+    }
+    readme.push_str(&format!("Files:       {}\n", files.len()));
+    readme.push_str(&format!("Total size:  {}\n", format_size(files.iter().filter_map(|file| file.size).sum())));
+    let description = fields.description.join(" ");
+    if !description.is_empty() {
+        readme.push_str(&format!("\n{}\n", plain_description(&description, MAX_README_DESCRIPTION)));
+    }
+    fs::write(output_dir.join(sidecar_name("README.txt", identifier, files)), readme)?;
+    Ok(metadata_path)
+}
+
+/// The name a sidecar file is written under: `name`, or `<identifier>_ia-get_<name>`
+/// when the item has a file called `name` in any case, so the sidecar never
+/// takes the place of one of the item's own files.
+fn sidecar_name(name: &str, identifier: &str, files: &[XmlFile]) -> String {
+    if files.iter().any(|file| file.name.eq_ignore_ascii_case(name)) {
+        format!("{}_ia-get_{}", identifier, name)
     } else {
-        base_new_url
+        name.to_string()
     }
 }
 
-fn calculate_md5(file_path: &str) -> Result<String, std::io::Error> {
-    let file_contents = fs::read(file_path)?;
-    let hash = md5::compute(&file_contents);
-    Ok(format!("{:x}", hash))
+/// The local path for a file of the item, refusing names that would be written
+/// outside `output_dir`, such as `../name` or an absolute path.
+fn confined_path(output_dir: &Path, name: &str) -> Result<PathBuf, IaGetError> {
+    let mut components = Path::new(name).components().peekable();
+    let confined = components.peek().is_some()
+        && components.all(|component| matches!(component, std::path::Component::Normal(_) | std::path::Component::CurDir));
+    if confined {
+        Ok(output_dir.join(name))
+    } else {
+        Err(IaGetError::UnsafePath { name: name.to_string() })
+    }
 }
 
-// Define the regular expression pattern for the expected format as a static constant
-static PATTERN: &str = r"^https:\/\/archive\.org\/details\/[a-zA-Z0-9_-]+$";
+/// Make a metadata value safe to use as a single path component.
+fn sanitize_path_component(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let sanitized = sanitized.trim().trim_matches('.');
+    if sanitized.is_empty() {
+        "_".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
+/// Whether an output template needs more than the identifier to be rendered.
+fn template_needs_metadata(template: &str) -> bool {
+    ["{collection}", "{creator}", "{date}", "{year}"]
+        .iter()
+        .any(|placeholder| template.contains(placeholder))
+}
 
-    let name = env!("CARGO_PKG_NAME");
-    let version = env!("CARGO_PKG_VERSION");
-    let authors = env!("CARGO_PKG_AUTHORS");
-    let description = env!("CARGO_PKG_DESCRIPTION");
+/// Expand `{identifier}`, `{collection}`, `{creator}`, `{date}` and `{year}` in an
+/// output template. Missing fields are replaced with `fallback` and every value
+/// is sanitized, so only `/` in the template itself separates directories.
+fn render_output_template(
+    template: &str,
+    identifier: &str,
+    fields: &ItemFields,
+    fallback: &str,
+) -> Result<PathBuf, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated placeholder in output template: {}", template))?;
+        let value = match &rest[start + 1..start + end] {
+            "identifier" => Some(identifier.to_string()),
+            "collection" => fields.collection.first().cloned(),
+            "creator" => fields.creator.first().cloned(),
+            "date" => fields.date.first().cloned(),
+            "year" => fields
+                .date
+                .first()
+                .and_then(|date| date.get(..4))
+                .filter(|year| year.chars().all(|c| c.is_ascii_digit()))
+                .map(str::to_string),
+            unknown => return Err(format!("Unknown placeholder in output template: {{{}}}", unknown)),
+        };
+        rendered.push_str(&sanitize_path_component(value.as_deref().unwrap_or(fallback)));
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(PathBuf::from(rendered))
+}
 
-    // Parse command line arguments
-    let matches = App::new(name)
-        .version(version)
-        .author(authors)
-        .about(description)
-        .arg(Arg::with_name("URL")
-             .help("URL to an archive.org details page")
-             .required(true)
-             .index(1))
-        .get_matches();
+/// Which files of an item to download, resolved once from the command line.
+///
+/// A file is selected when no include filters are given, or when its name ends
+/// with an included extension or its format is an included format. Excluded
+/// extensions always win. Comparisons ignore case and a leading `.`. Files of
+/// unknown size always pass the size limits, see `--preflight-sizes`.
+#[derive(Debug, Default)]
+struct FilterOptions {
+    include_ext: Vec<String>,
+    exclude_ext: Vec<String>,
+    include_formats: Vec<String>,
+    /// Patterns matched against the whole file name, from `--include-from` and `--exclude-from`.
+    include_globs: Vec<glob::Pattern>,
+    exclude_globs: Vec<glob::Pattern>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Companion extensions pulled in alongside a selected file with `--with-sidecars`.
+    sidecar_ext: Vec<String>,
+    /// Extensions or formats, best first, used to keep one copy of the same content.
+    prefer_formats: Vec<String>,
+}
 
-    let details_url = matches.value_of("URL").ok_or("Missing URL argument")?;
+impl FilterOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self, Box<dyn Error>> {
+        let values = |name: &str| -> Vec<String> {
+            matches
+                .values_of(name)
+                .into_iter()
+                .flatten()
+                .map(|value| value.trim().trim_start_matches('.').to_lowercase())
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+        // Read once per run, the pattern file may be a pipe
+        let (extra_include_ext, include_globs) = matches.value_of("include-from").map(read_pattern_file).transpose()?.unwrap_or_default();
+        let (extra_exclude_ext, exclude_globs) = matches.value_of("exclude-from").map(read_pattern_file).transpose()?.unwrap_or_default();
+        Ok(FilterOptions {
+            include_ext: [values("include-ext"), extra_include_ext].concat(),
+            exclude_ext: [values("exclude-ext"), extra_exclude_ext].concat(),
+            include_formats: values("include-formats"),
+            include_globs,
+            exclude_globs,
+            min_size: matches.value_of("min-size").and_then(|size| parse_size(size).ok()),
+            max_size: matches.value_of("max-size").and_then(|size| parse_size(size).ok()),
+            sidecar_ext: if matches.is_present("with-sidecars") { values("sidecar-ext") } else { Vec::new() },
+            prefer_formats: values("prefer-format"),
+        })
+    }
 
-    // Create a regex object with the static pattern
-    let regex = Regex::new(PATTERN)?;
+    fn is_empty(&self) -> bool {
+        self.include_ext.is_empty()
+            && self.exclude_ext.is_empty()
+            && self.include_formats.is_empty()
+            && self.include_globs.is_empty()
+            && self.exclude_globs.is_empty()
+            && self.prefer_formats.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+    }
 
-    println!("Archive.org URL: {}", details_url);
-    if !regex.is_match(details_url) {
-        println!("├╼ Archive.org URL is not in the expected format");
-        println!("╰╼ Expected format: https://archive.org/details/<identifier>/");
-        process::exit(1);
+    /// The active filters as command line flags.
+    fn describe(&self) -> Vec<String> {
+        let mut active: Vec<String> = [
+            ("--include-ext", &self.include_ext),
+            ("--exclude-ext", &self.exclude_ext),
+            ("--include-formats", &self.include_formats),
+            ("--prefer-format", &self.prefer_formats),
+        ]
+        .iter()
+        .filter(|(_, values)| !values.is_empty())
+        .map(|(flag, values)| format!("{} {}", flag, values.join(",")))
+        .collect();
+        for (flag, patterns) in [("--include-from", &self.include_globs), ("--exclude-from", &self.exclude_globs)] {
+            if !patterns.is_empty() {
+                let patterns: Vec<&str> = patterns.iter().map(glob::Pattern::as_str).collect();
+                active.push(format!("{} {}", flag, patterns.join(",")));
+            }
+        }
+        if let Some(size) = self.min_size {
+            active.push(format!("--min-size {}", format_size(size)));
+        }
+        if let Some(size) = self.max_size {
+            active.push(format!("--max-size {}", format_size(size)));
+        }
+        active
     }
 
-    match is_url_accessible(details_url).await {
-        Ok(_) => println!("╰╼ Archive.org URL online: 🟢"),
-        Err(e) => {
-            println!("├╼ Archive.org URL online: 🔴");
-            panic!  ("╰╼ Exiting due to error: {}", e);
+    fn selects(&self, file: &XmlFile) -> bool {
+        self.exclusion(file).is_none()
+    }
+
+    /// The first filter that rejects a file, described by its flag.
+    fn exclusion(&self, file: &XmlFile) -> Option<String> {
+        let name = file.name.to_lowercase();
+        let has_ext = |ext: &String| name.ends_with(&format!(".{}", ext));
+        if let Some(ext) = self.exclude_ext.iter().find(|ext| has_ext(ext)) {
+            return Some(format!("excluded by --exclude-ext {}", ext));
+        }
+        let has_match = |pattern: &&glob::Pattern| pattern.matches_with(&file.name, PATTERN_OPTIONS);
+        if let Some(pattern) = self.exclude_globs.iter().find(has_match) {
+            return Some(format!("excluded by --exclude-from pattern {}", pattern));
+        }
+        if let Some(size) = file.size {
+            if let Some(min) = self.min_size.filter(|min| size < *min) {
+                return Some(format!("{} is below --min-size {}", format_size(size), format_size(min)));
+            }
+            if let Some(max) = self.max_size.filter(|max| size > *max) {
+                return Some(format!("{} is above --max-size {}", format_size(size), format_size(max)));
+            }
+        }
+        if self.include_ext.is_empty() && self.include_formats.is_empty() && self.include_globs.is_empty() {
+            return None;
         }
+        let format = file.format_or_inferred().unwrap_or_default().to_lowercase();
+        if self.include_ext.iter().any(has_ext)
+            || self.include_formats.contains(&format)
+            || self.include_globs.iter().any(|pattern| has_match(&pattern))
+        {
+            return None;
+        }
+        let include: Vec<String> = self.describe().into_iter().filter(|flag| flag.starts_with("--include")).collect();
+        Some(format!("matches none of {}", include.join(" or ")))
     }
+}
 
-    let xml_url = get_xml_url(details_url);
-    println!("Archive.org XML: {}", xml_url);
+/// File name patterns match case-insensitively, and `*` also matches across directories.
+const PATTERN_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
 
-    match is_url_accessible(&xml_url).await {
-        Ok(_) => println!("├╼ Archive.org XML online: 🟢"),
-        Err(e) => {
-            println!("├╼ Archive.org XML online: 🔴");
-            panic!  ("╰╼ Exiting due to error: {}", e);
+/// Read a `--include-from` or `--exclude-from` file, one pattern per line with
+/// `#` comments. Lines with a wildcard or a path separator are globs matched
+/// against the whole file name, any other line is an extension.
+fn read_pattern_file(path: &str) -> Result<(Vec<String>, Vec<glob::Pattern>), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read pattern file {}: {}", path, e))?;
+    let mut extensions = Vec::new();
+    let mut patterns = Vec::new();
+    for line in list_entries(&text) {
+        if line.contains(['*', '?', '[', '/']) {
+            let pattern = glob::Pattern::new(&line).map_err(|e| format!("Invalid pattern {} in {}: {}", line, path, e))?;
+            patterns.push(pattern);
+        } else {
+            extensions.push(line.trim_start_matches('.').to_lowercase());
         }
     }
+    Ok((extensions, patterns))
+}
 
-    println!("├╼ Parsing XML file        👀");
-    // Get the base URL from the XML URL
-    let base_url = reqwest::Url::parse(&xml_url)?;
+/// A reviewed list of an item's files, written by `--plan-out` and downloaded by `--plan`.
+#[derive(Serialize, Deserialize, Debug)]
+struct DownloadPlan {
+    identifier: String,
+    files: Vec<PlanEntry>,
+}
 
-    // Download XML file
-    let response = reqwest::get(xml_url).await?.text().await?;
-    let files: XmlFiles = from_str(&response)?;
-    println!("╰╼ Done                    👍️");
+#[derive(Serialize, Deserialize, Debug)]
+struct PlanEntry {
+    name: String,
+    size: Option<u64>,
+    format: Option<String>,
+    md5: Option<String>,
+    /// Set for the files to download, initially those picked by the filters.
+    selected: bool,
+    /// Replace any local copy rather than resuming it, set when retrying a file that failed verification.
+    #[serde(default, skip_serializing)]
+    replace: bool,
+}
 
-    // Iterate over the XML files struct and print every field
-    for file in files.files {
-        // Create a clone of the base URL
-        let mut absolute_url = base_url.clone();
+impl DownloadPlan {
+    fn new(identifier: &str, files: &[XmlFile], filters: &FilterOptions) -> Self {
+        let selected: std::collections::HashSet<String> =
+            filter_files(files.to_vec(), filters).into_iter().map(|file| file.name).collect();
+        DownloadPlan {
+            identifier: identifier.to_string(),
+            files: files
+                .iter()
+                .map(|file| PlanEntry {
+                    name: file.name.clone(),
+                    size: file.size,
+                    format: file.format.clone(),
+                    md5: file.md5.clone(),
+                    selected: selected.contains(&file.name),
+                    replace: false,
+                })
+                .collect(),
+        }
+    }
+
+    fn read(path: &str) -> Result<Self, Box<dyn Error>> {
+        let plan = fs::read_to_string(path).map_err(|e| format!("failed to read plan {}: {}", path, e))?;
+        Ok(serde_json::from_str(&plan).map_err(|e| format!("invalid plan {}: {}", path, e))?)
+    }
 
-        // If the URL is relative, join it with the base_url to make it absolute
-        match absolute_url.join(&file.name) {
-            Ok(joined_url) => absolute_url = joined_url,
-            Err(_) => {} // If it's an error, it might already be an absolute URL. Ignore.
+    /// Keep the files selected in the plan, warning about any the item no longer has.
+    fn select(&self, files: Vec<XmlFile>) -> Vec<XmlFile> {
+        let selected: std::collections::HashSet<&str> =
+            self.files.iter().filter(|entry| entry.selected).map(|entry| entry.name.as_str()).collect();
+        for name in &selected {
+            if !files.iter().any(|file| file.name == *name) {
+                status!("├╼ Not in item: {}", name);
+            }
         }
-        println!(" ");
-        println!("📦️ Filename     {}", file.name);
-        let mut download_action = "╰╼ Downloading  ";
-        let mut download_complete = "├╼ Downloading  ";
+        files.into_iter().filter(|file| selected.contains(file.name.as_str())).collect()
+    }
+}
 
-        // Check if the file already exists
-        if Path::new(&file.name).exists() {
-            println!("├╼ Hash Check   🧮");
-            // Calculate the MD5 hash of the local file
-            let local_md5 = calculate_md5(&file.name).expect("╰╼ Failed to calculate MD5 hash");
-            let expected_md5 = file.md5.as_ref().unwrap();
-            if &local_md5 != expected_md5 {
-                download_action = "╰╼ Resuming     ";
-                download_complete = "├╼ Resuming     ";
-            } else {
-                println!("╰╼ Completed:   ✅");
-                continue;
+/// A file that could not be downloaded or verified, as listed by `--failures-out`.
+#[derive(Serialize, Deserialize, Debug)]
+struct FailedFile {
+    identifier: String,
+    name: String,
+    /// `checksum_mismatch`, `missing_checksum` or the kind of the error that stopped the download.
+    kind: String,
+    message: String,
+}
+
+/// Turn a failures list back into one plan per item, selecting just the failed files.
+fn retry_plans(path: &str) -> Result<Vec<DownloadPlan>, Box<dyn Error>> {
+    let failures = fs::read_to_string(path).map_err(|e| format!("failed to read retry file {}: {}", path, e))?;
+    let failures: Vec<FailedFile> = serde_json::from_str(&failures).map_err(|e| format!("invalid retry file {}: {}", path, e))?;
+    let mut plans: Vec<DownloadPlan> = Vec::new();
+    for failure in failures {
+        let entry = PlanEntry {
+            name: failure.name,
+            size: None,
+            format: None,
+            md5: None,
+            selected: true,
+            // Resuming a file with the wrong content would keep the wrong bytes
+            replace: failure.kind == "checksum_mismatch",
+        };
+        match plans.iter_mut().find(|plan| plan.identifier == failure.identifier) {
+            Some(plan) => plan.files.push(entry),
+            None => plans.push(DownloadPlan { identifier: failure.identifier, files: vec![entry] }),
+        }
+    }
+    Ok(plans)
+}
+
+/// Position of a file's extension or format in the `--prefer-format` list.
+fn preference_rank(file: &XmlFile, preferences: &[String]) -> Option<usize> {
+    let name = file.name.to_lowercase();
+    let format = file.format_or_inferred().unwrap_or_default().to_lowercase();
+    preferences
+        .iter()
+        .position(|preferred| name.ends_with(&format!(".{}", preferred)) || format == *preferred)
+}
+
+/// The name shared by every copy of the same content: derivatives are grouped
+/// with their original, and the last extension is dropped.
+fn content_group(file: &XmlFile) -> String {
+    let name = match (&file.source[..], &file.original) {
+        ("derivative", Some(original)) => original,
+        _ => &file.name,
+    };
+    let name = name.to_lowercase();
+    match name.rsplit_once('.') {
+        Some((stem, _)) => stem.to_string(),
+        None => name,
+    }
+}
+
+/// Among the `candidates` of the same content in formats on the preference list,
+/// keep only the best ranked. Candidates in formats that are not on the list are
+/// always kept, and files that are not candidates never are.
+fn prefer_formats(files: &[XmlFile], candidates: &[bool], preferences: &[String]) -> Vec<bool> {
+    let mut best: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    for (file, _) in files.iter().zip(candidates).filter(|(_, candidate)| **candidate) {
+        if let Some(rank) = preference_rank(file, preferences) {
+            let entry = best.entry(content_group(file)).or_insert(rank);
+            *entry = (*entry).min(rank);
+        }
+    }
+    files
+        .iter()
+        .zip(candidates)
+        .map(|(file, candidate)| {
+            *candidate
+                && match preference_rank(file, preferences) {
+                    Some(rank) => best.get(&content_group(file)) == Some(&rank),
+                    None => true,
+                }
+        })
+        .collect()
+}
+
+/// Keep only the files selected by `filters`, along with any sidecars of those
+/// files: files named like a selected file, such as `movie.srt` or `movie.en.vtt`
+/// for `movie.mp4`, with one of the sidecar extensions.
+fn filter_files(files: Vec<XmlFile>, filters: &FilterOptions) -> Vec<XmlFile> {
+    let selected: Vec<bool> = files.iter().map(|file| filters.selects(file)).collect();
+    // Formats only compete among the files the other filters select, so an
+    // excluded favourite does not take the format standing in for it along
+    let selected = if filters.prefer_formats.is_empty() { selected } else { prefer_formats(&files, &selected, &filters.prefer_formats) };
+    if filters.sidecar_ext.is_empty() {
+        return files.into_iter().zip(selected).filter_map(|(file, keep)| keep.then_some(file)).collect();
+    }
+
+    // File names without their last extension, followed by a dot
+    let stems: Vec<String> = files
+        .iter()
+        .zip(&selected)
+        .filter(|(_, keep)| **keep)
+        .filter_map(|(file, _)| file.name.to_lowercase().rsplit_once('.').map(|(stem, _)| format!("{}.", stem)))
+        .collect();
+    let is_sidecar = |file: &XmlFile| {
+        let name = file.name.to_lowercase();
+        !filters.exclude_ext.iter().any(|ext| name.ends_with(&format!(".{}", ext)))
+            && !filters.exclude_globs.iter().any(|pattern| pattern.matches_with(&file.name, PATTERN_OPTIONS))
+            && filters.sidecar_ext.iter().any(|ext| name.ends_with(&format!(".{}", ext)))
+            && stems.iter().any(|stem| name.starts_with(stem.as_str()))
+    };
+    files
+        .into_iter()
+        .zip(selected)
+        .filter_map(|(file, keep)| (keep || is_sidecar(&file)).then_some(file))
+        .collect()
+}
+
+/// Why a file is or is not selected, following the stages of `filter_files`,
+/// then `--range` and `--max-files`.
+#[derive(Serialize, Debug)]
+struct SelectionExplanation {
+    name: String,
+    selected: bool,
+    reason: String,
+}
+
+fn explain_selection(
+    files: &[XmlFile],
+    filters: &FilterOptions,
+    range: Option<(usize, Option<usize>)>,
+    max_files: Option<usize>,
+    name: &str,
+) -> SelectionExplanation {
+    let explanation = |selected: bool, reason: String| SelectionExplanation { name: name.to_string(), selected, reason };
+    let Some(file) = files.iter().find(|file| file.name == name) else {
+        return explanation(false, "not in the item's file list".to_string());
+    };
+    let mut selected = filter_files(files.to_vec(), filters);
+    let Some(mut position) = selected.iter().position(|kept| kept.name == name) else {
+        if let Some(reason) = filters.exclusion(file) {
+            return explanation(false, reason);
+        }
+        let group = content_group(file);
+        let better = selected
+            .iter()
+            .find(|kept| content_group(kept) == group && preference_rank(kept, &filters.prefer_formats).is_some())
+            .map(|kept| kept.name.as_str())
+            .unwrap_or_default();
+        return explanation(false, format!("--prefer-format keeps {} instead", better));
+    };
+    // --range slices the selection sorted by name, as download_prepared does
+    if let Some((start, end)) = range {
+        selected.sort_by(|a, b| a.name.cmp(&b.name));
+        let index = selected.iter().position(|kept| kept.name == name).unwrap_or_default();
+        let end_bound = end.unwrap_or(selected.len()).min(selected.len());
+        if !(start..end_bound).contains(&index) {
+            let end = end.map(|end| end.to_string()).unwrap_or_default();
+            return explanation(false, format!("index {} of the selection sorted by name is outside --range {}:{}", index, start, end));
+        }
+        position = index - start;
+    }
+    if let Some(max_files) = max_files.filter(|max_files| position >= *max_files) {
+        return explanation(false, format!("file {} of the selection is beyond --max-files {}", position + 1, max_files));
+    }
+    match filters.exclusion(file) {
+        Some(reason) => explanation(true, format!("included by --with-sidecars, although {}", reason)),
+        None => explanation(true, "included".to_string()),
+    }
+}
+
+/// Indicators of how complete and trustworthy an archive's file listing is.
+#[derive(Serialize, Debug)]
+struct HealthReport {
+    total_files: usize,
+    /// Files with no MD5 in the metadata, these cannot be verified.
+    missing_checksums: Vec<String>,
+    /// Files with no size in the metadata.
+    unknown_sizes: Vec<String>,
+    /// Files published with a size of zero bytes.
+    zero_byte_files: Vec<String>,
+    /// Pairs of names that differ only by case and collide on case-insensitive filesystems.
+    case_collisions: Vec<(String, String)>,
+    /// Percentage of files without any of the issues above.
+    completeness_score: f64,
+}
+
+fn health_report(files: &[XmlFile]) -> HealthReport {
+    let missing_checksums: Vec<String> = files
+        .iter()
+        .filter(|file| file.md5.is_none())
+        .map(|file| file.name.clone())
+        .collect();
+    let unknown_sizes: Vec<String> = files
+        .iter()
+        .filter(|file| file.size.is_none())
+        .map(|file| file.name.clone())
+        .collect();
+    let zero_byte_files: Vec<String> = files
+        .iter()
+        .filter(|file| file.size == Some(0))
+        .map(|file| file.name.clone())
+        .collect();
+
+    let mut seen: std::collections::HashMap<String, &str> = std::collections::HashMap::new();
+    let mut case_collisions = Vec::new();
+    for file in files {
+        match seen.get(&file.name.to_lowercase()) {
+            Some(first) if *first != file.name => case_collisions.push((first.to_string(), file.name.clone())),
+            Some(_) => {}
+            None => {
+                seen.insert(file.name.to_lowercase(), &file.name);
+            }
+        }
+    }
+
+    let flagged: std::collections::HashSet<&str> = missing_checksums
+        .iter()
+        .chain(&unknown_sizes)
+        .chain(&zero_byte_files)
+        .map(String::as_str)
+        .chain(case_collisions.iter().map(|(_, second)| second.as_str()))
+        .collect();
+    let completeness_score = if files.is_empty() {
+        0.0
+    } else {
+        (files.len() - flagged.len()) as f64 * 100.0 / files.len() as f64
+    };
+
+    HealthReport {
+        total_files: files.len(),
+        missing_checksums,
+        unknown_sizes,
+        zero_byte_files,
+        case_collisions,
+        completeness_score,
+    }
+}
+
+fn print_health_report(report: &HealthReport) {
+    summary!(" ");
+    summary!("🩺 Archive health {:.1}% of {} files without issues", report.completeness_score, report.total_files);
+    let sections = [
+        ("Missing MD5  ", &report.missing_checksums),
+        ("Unknown size ", &report.unknown_sizes),
+        ("Zero bytes   ", &report.zero_byte_files),
+    ];
+    for (label, names) in sections {
+        summary!("├╼ {} {}", label, names.len());
+        for name in names {
+            summary!("│  ├╼ {}", name);
+        }
+    }
+    summary!("╰╼ Case clash   {}", report.case_collisions.len());
+    for (first, second) in &report.case_collisions {
+        summary!("   ├╼ {} / {}", first, second);
+    }
+}
+
+/// Number and total size of an archive's files in one format.
+#[derive(Serialize, Debug)]
+struct FormatSummary {
+    format: String,
+    files: usize,
+    total_bytes: u64,
+}
+
+/// Group files by their archive.org format, largest total size first.
+fn format_report(files: &[XmlFile]) -> Vec<FormatSummary> {
+    let mut formats: std::collections::HashMap<&str, FormatSummary> = std::collections::HashMap::new();
+    for file in files {
+        let format = file.format_or_inferred().unwrap_or("Unknown");
+        let summary = formats.entry(format).or_insert_with(|| FormatSummary {
+            format: format.to_string(),
+            files: 0,
+            total_bytes: 0,
+        });
+        summary.files += 1;
+        summary.total_bytes += file.size.unwrap_or(0);
+    }
+    let mut report: Vec<FormatSummary> = formats.into_values().collect();
+    report.sort_by(|a, b| b.total_bytes.cmp(&a.total_bytes).then_with(|| a.format.cmp(&b.format)));
+    report
+}
+
+fn print_format_report(report: &[FormatSummary]) {
+    summary!(" ");
+    summary!("🗂️ Formats      {}", report.len());
+    for (index, summary) in report.iter().enumerate() {
+        let branch = if index + 1 == report.len() { "╰╼" } else { "├╼" };
+        summary!("{} {}: {} files, {}", branch, summary.format, summary.files, format_size(summary.total_bytes));
+    }
+}
+
+/// Differences between two fetches of the same archive's file listing.
+#[derive(Serialize, Debug, Default)]
+struct MetadataDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<FileChange>,
+}
+
+/// A file present in both listings whose size, mtime or checksum changed.
+#[derive(Serialize, Debug)]
+struct FileChange {
+    name: String,
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+    old_mtime: Option<u64>,
+    new_mtime: Option<u64>,
+    old_md5: Option<String>,
+    new_md5: Option<String>,
+}
+
+fn diff_metadata(old: &[XmlFile], new: &[XmlFile]) -> MetadataDiff {
+    let old_files: std::collections::BTreeMap<&str, &XmlFile> =
+        old.iter().map(|file| (file.name.as_str(), file)).collect();
+    let new_files: std::collections::BTreeMap<&str, &XmlFile> =
+        new.iter().map(|file| (file.name.as_str(), file)).collect();
+
+    let mut diff = MetadataDiff::default();
+    for (name, new_file) in &new_files {
+        match old_files.get(name) {
+            None => diff.added.push(name.to_string()),
+            Some(old_file) => {
+                if old_file.size != new_file.size || old_file.mtime_seconds() != new_file.mtime_seconds() || old_file.md5 != new_file.md5 {
+                    diff.modified.push(FileChange {
+                        name: name.to_string(),
+                        old_size: old_file.size,
+                        new_size: new_file.size,
+                        old_mtime: old_file.mtime_seconds(),
+                        new_mtime: new_file.mtime_seconds(),
+                        old_md5: old_file.md5.clone(),
+                        new_md5: new_file.md5.clone(),
+                    });
+                }
             }
         }
+    }
+    diff.removed = old_files
+        .keys()
+        .filter(|name| !new_files.contains_key(*name))
+        .map(|name| name.to_string())
+        .collect();
+    diff
+}
+
+/// A size change such as `+1.50 MiB` or `-512 B`.
+fn format_size_delta(old: u64, new: u64) -> String {
+    if new >= old {
+        format!("+{}", format_size(new - old))
+    } else {
+        format!("-{}", format_size(old - new))
+    }
+}
 
-        // Check if file.name includes a path
-        if let Some(path) = std::path::Path::new(&file.name).parent() {
-            // Create the local directory if it doesn't exist and path has a file name
-            if path.file_name().is_some() && !path.exists() {
-                fs::create_dir_all(path)?;
+/// Print the diff grouped by directory, with added files in green, removed
+/// files in red and modified files in yellow, unless colors are turned off.
+fn print_metadata_diff<'a>(diff: &'a MetadataDiff, old: &[XmlFile], new: &[XmlFile]) {
+    let size_of = |files: &[XmlFile], name: &str| {
+        files
+            .iter()
+            .find(|file| file.name == name)
+            .and_then(|file| file.size)
+            .map(format_size)
+            .unwrap_or_else(|| "unknown size".to_string())
+    };
+    let mut directories: std::collections::BTreeMap<&str, Vec<(&str, String)>> = std::collections::BTreeMap::new();
+    let mut add = |name: &'a str, line: String| {
+        let (directory, _) = name.rsplit_once('/').unwrap_or((".", name));
+        directories.entry(directory).or_default().push((name, line));
+    };
+    for name in &diff.added {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        add(name, console::style(format!("+ {} ({})", file, size_of(new, name))).green().to_string());
+    }
+    for name in &diff.removed {
+        let file = name.rsplit('/').next().unwrap_or(name);
+        add(name, console::style(format!("- {} ({})", file, size_of(old, name))).red().to_string());
+    }
+    for change in &diff.modified {
+        let mut fields = Vec::new();
+        match (change.old_size, change.new_size) {
+            (Some(old), Some(new)) if old != new => {
+                fields.push(format!("{} -> {} [{}]", format_size(old), format_size(new), format_size_delta(old, new)))
             }
+            (old, new) if old != new => fields.push("size".to_string()),
+            _ => {}
+        }
+        if change.old_mtime != change.new_mtime {
+            fields.push("mtime".to_string());
+        }
+        if change.old_md5 != change.new_md5 {
+            fields.push("md5".to_string());
+        }
+        let file = change.name.rsplit('/').next().unwrap_or(&change.name);
+        add(&change.name, console::style(format!("~ {} ({})", file, fields.join(", "))).yellow().to_string());
+    }
+
+    summary!(" ");
+    summary!(
+        "🔀 Changes      {} added, {} removed, {} modified",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len()
+    );
+    for (directory, mut lines) in directories {
+        lines.sort();
+        summary!("├╼ 📁 {}/", directory);
+        for (_, line) in lines {
+            summary!("│  ├╼ {}", line);
         }
+    }
+    summary!("╰╼ Done");
+}
+
+// Exit codes, documented in the README so scripts can tell failures apart
+const EXIT_FAILURE: i32 = 1;
+const EXIT_INVALID_ARGS: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_NETWORK: i32 = 4;
+const EXIT_PARTIAL_FAILURE: i32 = 5;
+const EXIT_CHECKSUM_FAILURE: i32 = 6;
+const EXIT_NO_FILES: i32 = 7;
+const EXIT_NO_SPACE: i32 = 8;
+const EXIT_RESTRICTED: i32 = 9;
+const EXIT_TIMED_OUT: i32 = 10;
+const EXIT_PERMISSION_DENIED: i32 = 11;
+const EXIT_PATH_TOO_LONG: i32 = 12;
+
+/// Map an error that ended a download to the exit code reported for it.
+fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    if error.downcast_ref::<IdentifierError>().is_some() {
+        return EXIT_INVALID_ARGS;
+    }
+    match error.downcast_ref::<IaGetError>() {
+        Some(IaGetError::NotFound) => EXIT_NOT_FOUND,
+        Some(IaGetError::RetryBudgetExhausted { .. }) => EXIT_PARTIAL_FAILURE,
+        Some(IaGetError::NoFiles { .. }) => EXIT_NO_FILES,
+        Some(IaGetError::InsufficientSpace { .. } | IaGetError::DiskFull { .. }) => EXIT_NO_SPACE,
+        Some(IaGetError::PermissionDenied { .. }) => EXIT_PERMISSION_DENIED,
+        Some(IaGetError::PathTooLong { .. }) => EXIT_PATH_TOO_LONG,
+        Some(IaGetError::Restricted { .. }) => EXIT_RESTRICTED,
+        Some(IaGetError::TimedOut { .. }) => EXIT_TIMED_OUT,
+        Some(e) if e.is_transient() => EXIT_NETWORK,
+        Some(_) => EXIT_FAILURE,
+        // Request errors that were not converted to an IaGetError
+        None if error.downcast_ref::<reqwest::Error>().is_some() => EXIT_NETWORK,
+        None => EXIT_FAILURE,
+    }
+}
+
+/// Short machine readable name for the kind of an error.
+fn error_kind(error: &(dyn Error + 'static)) -> &'static str {
+    if error.downcast_ref::<IdentifierError>().is_some() {
+        return "invalid_identifier";
+    }
+    match error.downcast_ref::<IaGetError>() {
+        Some(IaGetError::NotFound) => "not_found",
+        Some(IaGetError::Forbidden { .. }) => "forbidden",
+        Some(IaGetError::RateLimited { .. }) => "rate_limited",
+        Some(IaGetError::ServerError { .. }) => "server_error",
+        Some(IaGetError::HttpStatus { .. }) => "http_status",
+        Some(IaGetError::RedirectNotAllowed { .. }) => "redirect_not_allowed",
+        Some(IaGetError::ReadTimeout { .. }) => "read_timeout",
+        Some(IaGetError::TransferTimeout { .. }) => "transfer_timeout",
+        Some(IaGetError::Network(_)) => "network",
+        Some(IaGetError::RetryBudgetExhausted { .. }) => "retry_budget_exhausted",
+        Some(IaGetError::NoFiles { .. }) => "no_files",
+        Some(IaGetError::InsufficientSpace { .. }) => "insufficient_space",
+        Some(IaGetError::Restricted { .. }) => "restricted",
+        Some(IaGetError::TimedOut { .. }) => "timed_out",
+        Some(IaGetError::DiskFull { .. }) => "disk_full",
+        Some(IaGetError::PermissionDenied { .. }) => "permission_denied",
+        Some(IaGetError::PathTooLong { .. }) => "path_too_long",
+        Some(IaGetError::UnsafePath { .. }) => "unsafe_path",
+        None if error.downcast_ref::<reqwest::Error>().is_some() => "network",
+        None if error.downcast_ref::<std::io::Error>().is_some() => "io",
+        None => "other",
+    }
+}
+
+/// Report an error that stopped an item or the run. With `--json-errors` it is
+/// written to stderr as a single line JSON object instead of a status line.
+fn report_error(action: &str, error: &(dyn Error + 'static), identifier: Option<&str>) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let report = serde_json::json!({
+            "error": {
+                "kind": error_kind(error),
+                "message": error.to_string(),
+                "identifier": identifier,
+            }
+        });
+        eprintln!("{}", report);
+    } else if SIMPLE_PROGRESS.load(Ordering::Relaxed) {
+        finish_simple_line();
+        eprintln!("{} due to error: {}", action, error);
+    } else if let (true, Some(identifier)) = (SUMMARY_ONLY.load(Ordering::Relaxed), identifier) {
+        // Without the status messages nothing else says which item failed
+        summary!("╰╼ {} {} due to error: {}", action, identifier, error);
+    } else {
+        summary!("╰╼ {} due to error: {}", action, error);
+    }
+}
 
-        // Create a new file for writing
-        let mut download = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&file.name)?;
+/// Overall progress across every file in the archive.
+///
+/// Progress and ETA are weighted by bytes, so one huge file among many tiny
+/// ones is not reported as nearly done. File counts are tracked separately.
+struct DownloadStats {
+    total_files: usize,
+    completed_files: usize,
+    /// Sum of the sizes published in the metadata.
+    total_bytes: u64,
+    /// Files whose size is not published, these only count towards file progress.
+    unknown_size_files: usize,
+    /// Bytes of completed files, including files that were already present.
+    completed_bytes: u64,
+    /// Bytes actually transferred during this run, used to estimate the rate.
+    transferred_bytes: u64,
+    /// Transfers retried after a transient failure.
+    retries: u64,
+    started: Instant,
+}
 
-        // Get the size of the local file if it already exists
-        let file_size = download.metadata()?.len();
-        if file_size > 0 {
-            // Set the starting position for resuming the download
-            download.seek(std::io::SeekFrom::Start(file_size))?;
+impl DownloadStats {
+    fn new(files: &[XmlFile]) -> Self {
+        DownloadStats {
+            total_files: files.len(),
+            completed_files: 0,
+            total_bytes: files.iter().filter_map(|file| file.size).sum(),
+            unknown_size_files: files.iter().filter(|file| file.size.is_none()).count(),
+            completed_bytes: 0,
+            transferred_bytes: 0,
+            retries: 0,
+            started: Instant::now(),
         }
+    }
 
-        // Set the Range header to specify the starting offset
-        let mut initial_request = client.get(absolute_url);
-        let range_header = format!("bytes={}-", file_size);
-        let mut headers = HeaderMap::new();
-        headers.insert(reqwest::header::RANGE, HeaderValue::from_str(&range_header)?);
-        initial_request = initial_request.headers(headers);
+    /// Record a finished file and the number of bytes fetched for it this run.
+    fn complete_file(&mut self, file: &XmlFile, transferred: u64) {
+        self.completed_files += 1;
+        self.completed_bytes += file.size.unwrap_or(0);
+        self.transferred_bytes += transferred;
+    }
+
+    /// Percentage complete by bytes, falling back to file count when no sizes are known.
+    fn percent(&self) -> f64 {
+        if self.total_bytes > 0 {
+            self.completed_bytes.min(self.total_bytes) as f64 * 100.0 / self.total_bytes as f64
+        } else if self.total_files > 0 {
+            self.completed_files as f64 * 100.0 / self.total_files as f64
+        } else {
+            100.0
+        }
+    }
 
-        let mut response = initial_request.send().await?;
+    /// Estimated time remaining, based on the transfer rate observed so far.
+    fn eta(&self) -> Option<Duration> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if self.transferred_bytes == 0 || elapsed <= 0.0 {
+            return None;
+        }
+        let remaining = self.total_bytes.saturating_sub(self.completed_bytes);
+        if remaining == 0 {
+            return None;
+        }
+        let rate = self.transferred_bytes as f64 / elapsed;
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
 
-        // Get the content length from the response headers
-        let content_length = response.content_length().unwrap_or(0);
-        let pb = ProgressBar::new(content_length + file_size);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(format!("{}{{elapsed_precise}}     {{bar:40.green/green}} {{bytes}}/{{total_bytes}} (ETA: {{eta}})", download_action).as_str()).expect("REASON")
-                .progress_chars("▓▒░"),
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "{:.1}% of {} ({}/{} files",
+            self.percent(),
+            format_size(self.total_bytes),
+            self.completed_files,
+            self.total_files
         );
+        if self.unknown_size_files > 0 {
+            summary.push_str(&format!(", {} of unknown size", self.unknown_size_files));
+        }
+        summary.push(')');
+        if self.retries > 0 {
+            summary.push_str(&format!(" {} retries", self.retries));
+        }
+        if let Some(eta) = self.eta() {
+            summary.push_str(&format!(" ETA: {}", HumanDuration(eta)));
+        }
+        summary
+    }
+}
+
+/// Errors raised by ia-get, classified so a 404 can be told apart from a 503.
+#[derive(Debug)]
+enum IaGetError {
+    /// The server answered 404, the item or file does not exist.
+    NotFound,
+    /// The server answered 401 or 403, access is restricted.
+    Forbidden { status: u16 },
+    /// The server answered 429, `retry_after` holds the requested delay in seconds.
+    RateLimited { retry_after: Option<u64> },
+    /// The server answered with a 5xx status.
+    ServerError { status: u16 },
+    /// Any other unsuccessful status.
+    HttpStatus { status: u16 },
+    /// A redirect pointed at a host outside the allowlist.
+    RedirectNotAllowed { url: String },
+    /// No data arrived within the read timeout.
+    ReadTimeout { seconds: u64 },
+    /// A file took longer than `--min-expected-rate` allows.
+    TransferTimeout { seconds: u64 },
+    /// The request never produced a response.
+    Network(reqwest::Error),
+    /// The run gave up after retrying transient failures `limit` times in total.
+    RetryBudgetExhausted { limit: u64 },
+    /// Nothing to download, the item has no files or `filtered_out` were all excluded.
+    NoFiles { filtered_out: usize },
+    /// The output filesystem has `available` bytes free but `required` are needed.
+    InsufficientSpace { available: u64, required: u64 },
+    /// The item metadata marks it as dark, or its files as access restricted.
+    Restricted { dark: bool },
+    /// The run was stopped when it reached `--max-duration`.
+    TimedOut { limit: Duration },
+    /// Writing `path` failed because the disk or quota is full.
+    DiskFull { path: PathBuf },
+    /// Writing `path` was refused by the filesystem permissions.
+    PermissionDenied { path: PathBuf },
+    /// `path` is longer than the filesystem or platform allows.
+    PathTooLong { path: PathBuf },
+    /// A file name in the metadata would be written outside the output directory.
+    UnsafePath { name: String },
+}
+
+impl IaGetError {
+    /// Whether the failure may go away if the request is tried again.
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            IaGetError::RateLimited { .. }
+                | IaGetError::ServerError { .. }
+                | IaGetError::ReadTimeout { .. }
+                | IaGetError::TransferTimeout { .. }
+                | IaGetError::Network(_)
+        )
+    }
+}
 
-        // Download the remaining chunks and update the progress bar
-        let mut total_bytes: u64 = file_size;
-        while let Some(chunk) = response.chunk().await? {
-            download.write_all(&chunk)?;
-            total_bytes += chunk.len() as u64;
-            pb.set_position(total_bytes);
+impl std::fmt::Display for IaGetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IaGetError::NotFound => write!(f, "not found (HTTP 404)"),
+            IaGetError::Forbidden { status } => write!(f, "access forbidden (HTTP {})", status),
+            IaGetError::RateLimited { retry_after: Some(seconds) } => {
+                write!(f, "rate limited (HTTP 429), retry after {} seconds", seconds)
+            }
+            IaGetError::RateLimited { retry_after: None } => write!(f, "rate limited (HTTP 429)"),
+            IaGetError::ServerError { status } => write!(f, "server error (HTTP {})", status),
+            IaGetError::HttpStatus { status } => write!(f, "unexpected response (HTTP {})", status),
+            IaGetError::RedirectNotAllowed { url } => {
+                write!(f, "refusing to follow redirect to {}, the host is not allowed", url)
+            }
+            IaGetError::ReadTimeout { seconds } => write!(f, "no data received for {} seconds", seconds),
+            IaGetError::TransferTimeout { seconds } => {
+                write!(f, "transfer not finished within {} seconds, slower than --min-expected-rate", seconds)
+            }
+            IaGetError::Network(e) => write!(f, "network error: {}", e),
+            IaGetError::RetryBudgetExhausted { limit } => {
+                write!(f, "giving up after {} retries in total", limit)
+            }
+            IaGetError::NoFiles { filtered_out: 0 } => write!(f, "the item has no files"),
+            IaGetError::NoFiles { filtered_out } => {
+                write!(f, "all {} files were filtered out", filtered_out)
+            }
+            IaGetError::Restricted { dark: true } => write!(f, "the item is dark and not available for download"),
+            IaGetError::Restricted { dark: false } => {
+                write!(f, "the item is access restricted, its files cannot be downloaded")
+            }
+            IaGetError::TimedOut { limit } => write!(f, "stopped at the maximum duration of {}", HumanDuration(*limit)),
+            IaGetError::InsufficientSpace { available, required } => write!(
+                f,
+                "not enough free space, {} available but {} required",
+                format_size(*available),
+                format_size(*required)
+            ),
+            IaGetError::DiskFull { path } => {
+                write!(f, "the disk is full writing {}, free some space or choose another output directory", path.display())
+            }
+            IaGetError::PermissionDenied { path } => write!(
+                f,
+                "permission denied writing {}, check the output directory is writable by this user",
+                path.display()
+            ),
+            IaGetError::UnsafePath { name } => {
+                write!(f, "refusing to download {}, its name leads outside the output directory", name)
+            }
+            IaGetError::PathTooLong { path } if cfg!(windows) => write!(
+                f,
+                "the path {} is too long, use a shorter output directory or --output-template, or enable long paths in Windows",
+                path.display()
+            ),
+            IaGetError::PathTooLong { path } => write!(
+                f,
+                "the path {} is too long, use a shorter output directory or --output-template",
+                path.display()
+            ),
         }
+    }
+}
 
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(format!("{}{{elapsed_precise}}     {{bar:40.green/green}} {{total_bytes}}", download_complete).as_str()).expect("REASON")
-        );
-        pb.finish();
-
-        println!("├╼ Hash Check   🧮");
-        // Calculate the MD5 hash of the local file
-        let local_md5 = calculate_md5(&file.name).expect("╰╼ Failed to calculate MD5 hash");
-        let expected_md5 = file.md5.as_ref().unwrap();
-        if &local_md5 != expected_md5 {
-            println!("╰╼ Failure:     ❌");
-        } else {
-            println!("╰╼ Success:     ✅");
+/// Turn a failure to write `path` into a specific error when the disk is full,
+/// permission is denied or the path is too long, so the user gets advice on
+/// how to fix it. Anything else is passed on unchanged.
+fn file_system_error(error: std::io::Error, path: &Path) -> Box<dyn Error> {
+    use std::io::ErrorKind;
+    // Windows reports an overlong path as not found
+    const WINDOWS_PATH_NOT_FOUND: i32 = 3;
+    const WINDOWS_MAX_PATH: usize = 260;
+    let path = path.to_path_buf();
+    match error.kind() {
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => IaGetError::DiskFull { path }.into(),
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => IaGetError::PermissionDenied { path }.into(),
+        ErrorKind::InvalidFilename => IaGetError::PathTooLong { path }.into(),
+        _ if cfg!(windows)
+            && error.raw_os_error() == Some(WINDOWS_PATH_NOT_FOUND)
+            && path.as_os_str().len() >= WINDOWS_MAX_PATH =>
+        {
+            IaGetError::PathTooLong { path }.into()
         }
+        _ => error.into(),
     }
+}
 
-    Ok(())
+impl Error for IaGetError {}
+
+impl From<reqwest::Error> for IaGetError {
+    fn from(e: reqwest::Error) -> Self {
+        let mut source = e.source();
+        while let Some(cause) = source {
+            if let Some(redirect) = cause.downcast_ref::<DisallowedRedirect>() {
+                return IaGetError::RedirectNotAllowed { url: redirect.0.clone() };
+            }
+            source = cause.source();
+        }
+        IaGetError::Network(e)
+    }
+}
+
+/// Hosts that redirects may lead to, matching the host itself or any subdomain.
+const DEFAULT_REDIRECT_HOSTS: &[&str] = &["archive.org"];
+
+/// Raised by the redirect policy when a redirect leaves the allowed hosts.
+#[derive(Debug)]
+struct DisallowedRedirect(String);
+
+impl std::fmt::Display for DisallowedRedirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "redirect to {} is not allowed", self.0)
+    }
+}
+
+impl Error for DisallowedRedirect {}
+
+fn host_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts
+        .iter()
+        .any(|allowed| host == allowed || host.ends_with(&format!(".{}", allowed)))
+}
+
+/// Timeouts applied to the HTTP clients.
+///
+/// The connect timeout applies to every request. The overall timeout only
+/// applies to metadata requests; file downloads can legitimately take hours, so
+/// they instead rely on the read timeout, which acts as a stall detector and
+/// aborts a transfer when no data arrives for that long. A value of zero
+/// disables a timeout.
+///
+/// Redirects are only followed to archive.org, its subdomains, which include
+/// the mirrors and CDN hosts, and any extra hosts allowed on the command line.
+struct ClientConfig {
+    user_agent: String,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    /// Slowest acceptable download rate in bytes per second, see `transfer_timeout`.
+    min_expected_rate: Option<u64>,
+    allowed_hosts: Vec<String>,
+}
+
+impl ClientConfig {
+    fn from_matches(matches: &ArgMatches) -> Self {
+        let seconds = |name: &str| {
+            let value: u64 = matches.value_of_t(name).unwrap_or_else(|e| e.exit());
+            (value > 0).then(|| Duration::from_secs(value))
+        };
+        ClientConfig {
+            user_agent: user_agent(matches),
+            connect_timeout: seconds("connect-timeout"),
+            read_timeout: seconds("read-timeout"),
+            request_timeout: seconds("timeout"),
+            min_expected_rate: matches.value_of("min-expected-rate").and_then(|rate| parse_size(rate).ok()),
+            allowed_hosts: DEFAULT_REDIRECT_HOSTS
+                .iter()
+                .map(|host| host.to_string())
+                .chain(matches.values_of("allow-redirect-host").into_iter().flatten().map(str::to_string))
+                .collect(),
+        }
+    }
+
+    /// Time allowed to transfer `bytes` with `--min-expected-rate`: the request
+    /// timeout plus the time the bytes take at that rate, so large files get a
+    /// proportionally longer window. The read timeout still catches stalls.
+    fn transfer_timeout(&self, bytes: u64) -> Option<Duration> {
+        let rate = self.min_expected_rate?.max(1);
+        Some(self.request_timeout.unwrap_or_default() + Duration::from_secs_f64(bytes as f64 / rate as f64))
+    }
+
+    fn builder(&self) -> reqwest::ClientBuilder {
+        let allowed_hosts = self.allowed_hosts.clone();
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                return attempt.error("too many redirects");
+            }
+            match attempt.url().host_str() {
+                Some(host) if host_allowed(host, &allowed_hosts) => attempt.follow(),
+                _ => {
+                    let url = attempt.url().to_string();
+                    attempt.error(DisallowedRedirect(url))
+                }
+            }
+        });
+        let mut builder = Client::builder().user_agent(self.user_agent.as_str()).redirect(policy);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        builder
+    }
+
+    /// Client for metadata and availability checks, bounded by the overall timeout.
+    fn metadata_client(&self) -> reqwest::Result<Client> {
+        let mut builder = self.builder();
+        if let Some(timeout) = self.request_timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder.build()
+    }
+
+    /// Client for file downloads, which has no overall timeout.
+    fn download_client(&self) -> reqwest::Result<Client> {
+        self.builder().build()
+    }
+}
+
+/// Pick the User-Agent sent with every request: the `--user-agent` flag, then the
+/// `IA_GET_USER_AGENT` environment variable, then the default. An override is
+/// used verbatim, with a warning if it carries no contact details as
+/// archive.org asks of automated clients.
+fn user_agent(matches: &ArgMatches) -> String {
+    let custom = matches
+        .value_of("user-agent")
+        .map(str::to_string)
+        .or_else(|| std::env::var("IA_GET_USER_AGENT").ok().filter(|value| !value.is_empty()));
+    match custom {
+        Some(agent) => {
+            if !agent.contains('@') && !agent.contains("http") {
+                status!("Warning: User-Agent has no contact email or URL, archive.org may throttle it");
+            }
+            agent
+        }
+        None => format!("{}/{} (+https://github.com/wimpysworld/ia-get)", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// Fetch the next chunk of a response, failing if it stalls for longer than `read_timeout`.
+async fn next_chunk(response: &mut reqwest::Response, read_timeout: Option<Duration>) -> Result<Option<bytes::Bytes>, IaGetError> {
+    match read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, response.chunk())
+            .await
+            .map_err(|_| IaGetError::ReadTimeout { seconds: timeout.as_secs() })?
+            .map_err(IaGetError::from),
+        None => response.chunk().await.map_err(IaGetError::from),
+    }
+}
+
+/// Send `request`, failing if the server has not answered within `read_timeout`.
+async fn send(request: reqwest::RequestBuilder, read_timeout: Option<Duration>) -> Result<reqwest::Response, IaGetError> {
+    match read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, request.send())
+            .await
+            .map_err(|_| IaGetError::ReadTimeout { seconds: timeout.as_secs() })?
+            .map_err(IaGetError::from),
+        None => request.send().await.map_err(IaGetError::from),
+    }
+}
+
+/// Map an unsuccessful HTTP response to the matching `IaGetError`.
+fn check_response(response: reqwest::Response) -> Result<reqwest::Response, IaGetError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    Err(match status.as_u16() {
+        404 => IaGetError::NotFound,
+        code @ (401 | 403) => IaGetError::Forbidden { status: code },
+        429 => IaGetError::RateLimited {
+            retry_after: response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok()),
+        },
+        code @ 500..=599 => IaGetError::ServerError { status: code },
+        code => IaGetError::HttpStatus { status: code },
+    })
+}
+
+async fn is_url_accessible(client: &Client, url: &str) -> Result<(), IaGetError> {
+    let response = client.get(url).send().await?;
+    check_response(response)?;
+    Ok(())
+}
+
+/// Archive.org identifiers are at most this many characters long.
+const MAX_IDENTIFIER_LENGTH: usize = 100;
+
+/// Why a command line argument could not be turned into an archive.org identifier.
+#[derive(Debug, PartialEq)]
+enum IdentifierError {
+    Empty,
+    TooLong { length: usize },
+    IllegalCharacter { character: char },
+    LeadingSeparator { character: char },
+    TrailingSeparator { character: char },
+    /// A Wayback Machine URL, which archives web pages rather than items.
+    WaybackUrl,
+    NotArchiveUrl { host: String },
+    /// An archive.org URL that does not point at an item.
+    NoItemInUrl,
+}
+
+impl std::fmt::Display for IdentifierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentifierError::Empty => write!(f, "No identifier given"),
+            IdentifierError::TooLong { length } => write!(
+                f,
+                "Identifier is {} characters long, archive.org identifiers are at most {}",
+                length, MAX_IDENTIFIER_LENGTH
+            ),
+            IdentifierError::IllegalCharacter { character } => write!(
+                f,
+                "Identifier contains {:?}, only letters, numbers, '_', '-' and '.' are allowed",
+                character
+            ),
+            IdentifierError::LeadingSeparator { character } => {
+                write!(f, "Identifier cannot start with {:?}", character)
+            }
+            IdentifierError::TrailingSeparator { character } => {
+                write!(f, "Identifier cannot end with {:?}", character)
+            }
+            IdentifierError::WaybackUrl => write!(
+                f,
+                "Wayback Machine URLs are not supported, use the URL of an archive.org item: https://archive.org/details/<identifier>"
+            ),
+            IdentifierError::NotArchiveUrl { host } => write!(
+                f,
+                "{} is not archive.org, expected https://archive.org/details/<identifier>",
+                host
+            ),
+            IdentifierError::NoItemInUrl => write!(
+                f,
+                "URL does not point at an item, expected https://archive.org/details/<identifier>"
+            ),
+        }
+    }
+}
+
+impl Error for IdentifierError {}
+
+fn validate_identifier(identifier: &str) -> Result<(), IdentifierError> {
+    let length = identifier.chars().count();
+    if length == 0 {
+        return Err(IdentifierError::Empty);
+    }
+    if length > MAX_IDENTIFIER_LENGTH {
+        return Err(IdentifierError::TooLong { length });
+    }
+    if let Some(character) = identifier
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        return Err(IdentifierError::IllegalCharacter { character });
+    }
+    let is_separator = |c: char| matches!(c, '-' | '.');
+    match (identifier.chars().next(), identifier.chars().last()) {
+        (Some(character), _) if is_separator(character) => Err(IdentifierError::LeadingSeparator { character }),
+        (_, Some(character)) if is_separator(character) => Err(IdentifierError::TrailingSeparator { character }),
+        _ => Ok(()),
+    }
+}
+
+/// Extract and validate the identifier from a bare identifier or an archive.org
+/// details or download URL. Anything after the identifier, such as a file name
+/// pasted from a download link, a query or a fragment, is ignored.
+fn normalize_archive_identifier(input: &str) -> Result<String, IdentifierError> {
+    let input = input.trim();
+    let looks_like_url = input.contains("://")
+        || ["archive.org/", "www.archive.org/", "web.archive.org/"]
+            .iter()
+            .any(|prefix| input.starts_with(prefix));
+    if !looks_like_url {
+        validate_identifier(input)?;
+        return Ok(input.to_string());
+    }
+
+    let with_scheme = if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("https://{}", input)
+    };
+    let url = reqwest::Url::parse(&with_scheme).map_err(|_| IdentifierError::NoItemInUrl)?;
+    match url.host_str() {
+        Some("archive.org") | Some("www.archive.org") => {}
+        Some("web.archive.org") => return Err(IdentifierError::WaybackUrl),
+        Some(host) => return Err(IdentifierError::NotArchiveUrl { host: host.to_string() }),
+        None => return Err(IdentifierError::NoItemInUrl),
+    }
+
+    let mut segments = url.path_segments().into_iter().flatten().filter(|segment| !segment.is_empty());
+    match (segments.next(), segments.next()) {
+        (Some("details") | Some("download"), Some(identifier)) => {
+            validate_identifier(identifier)?;
+            Ok(identifier.to_string())
+        }
+        _ => Err(IdentifierError::NoItemInUrl),
+    }
+}
+
+fn get_xml_url(original_url: &str) -> String {
+    let base_new_url = original_url.replacen("details", "download", 1);
+    if let Some(last_segment) = original_url.split('/').next_back() {
+        format!("{}/{}_files.xml", base_new_url, last_segment)
+    } else {
+        base_new_url
+    }
+}
+
+/// Fetch the XML metadata, asking the server for gzip unless `compress` is false.
+///
+/// Only metadata is ever requested compressed; file downloads always ask for the
+/// identity encoding so that byte ranges and content lengths stay meaningful.
+async fn fetch_xml(client: &Client, xml_url: &str, compress: bool, verbose: bool, log: &mut Vec<String>) -> Result<String, Box<dyn Error>> {
+    let mut request = client.get(xml_url);
+    if compress {
+        request = request.header(reqwest::header::ACCEPT_ENCODING, "gzip");
+    }
+    let response = check_response(request.send().await?)?;
+
+    let gzipped = response
+        .headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("gzip"))
+        .unwrap_or(false);
+    let body = response.bytes().await?;
+
+    if !gzipped {
+        if verbose {
+            log.push(format!("├╼ XML size                {} bytes (uncompressed)", body.len()));
+        }
+        return Ok(String::from_utf8(body.to_vec())?);
+    }
+
+    let mut xml = String::new();
+    GzDecoder::new(&body[..]).read_to_string(&mut xml)?;
+    if verbose {
+        log.push(format!("├╼ XML size                {} bytes (gzip: {} bytes)", xml.len(), body.len()));
+    }
+    Ok(xml)
+}
+
+/// Build the download URL for `file_name` within the item at `item_url`.
+///
+/// `/` in the file name separates path segments. Every segment is
+/// percent-encoded on its own, so spaces, `#`, `?`, `%` and non-ASCII
+/// characters cannot be mistaken for a fragment, a query or an escape.
+/// A `..` segment is refused, URLs treat it as the parent directory even
+/// when percent-encoded, so it would name a file outside the item.
+fn construct_download_url(item_url: &reqwest::Url, file_name: &str) -> Result<reqwest::Url, IaGetError> {
+    if file_name.split('/').any(|segment| segment == "..") {
+        return Err(IaGetError::UnsafePath { name: file_name.to_string() });
+    }
+    let mut url = item_url.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend(file_name.split('/'));
+    }
+    Ok(url)
+}
+
+/// Format a number of bytes for display, as `1.00 MiB` or with `--units si` as `1.05 MB`.
+fn format_size(bytes: u64) -> String {
+    if SI_UNITS.load(Ordering::Relaxed) {
+        DecimalBytes(bytes).to_string()
+    } else {
+        HumanBytes(bytes).to_string()
+    }
+}
+
+/// Parse a size such as `512`, `500K`, `1.5M` or `2GiB` into bytes. `KiB` style
+/// units are always 1024 based, `K` and `KB` follow `--units` like `format_size`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {}", value))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let base: u64 = if unit.contains('I') || !SI_UNITS.load(Ordering::Relaxed) { 1024 } else { 1000 };
+    let exponent = match unit.as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KI" | "KIB" => 1,
+        "M" | "MB" | "MI" | "MIB" => 2,
+        "G" | "GB" | "GI" | "GIB" => 3,
+        "T" | "TB" | "TI" | "TIB" => 4,
+        _ => return Err(format!("Invalid size unit: {}", value)),
+    };
+    Ok((number * base.pow(exponent) as f64) as u64)
+}
+
+/// Parse a duration such as `90`, `90s`, `30m`, `2h` or `1d`, in seconds without a unit.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid duration: {}", value))?;
+    let seconds = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Invalid duration unit: {}", value)),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration too long: {}", value))
+}
+
+/// Parse a `START:END` slice of the file list, either end may be left out.
+fn parse_range(value: &str) -> Result<(usize, Option<usize>), String> {
+    let (start, end) = value.split_once(':').ok_or_else(|| format!("Invalid range, expected START:END: {}", value))?;
+    let start = match start.trim() {
+        "" => 0,
+        start => start.parse().map_err(|_| format!("Invalid range start: {}", start))?,
+    };
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse().map_err(|_| format!("Invalid range end: {}", end))?),
+    };
+    match end {
+        Some(end) if end < start => Err(format!("Invalid range, end is before start: {}", value)),
+        _ => Ok((start, end)),
+    }
+}
+
+/// The most connections or requests any one concurrency option may open at once.
+const MAX_CONCURRENCY: usize = 16;
+
+/// Parse a count of concurrent connections or requests, from 1 to `MAX_CONCURRENCY`.
+fn parse_concurrency(value: &str) -> Result<usize, String> {
+    match value.trim().parse() {
+        Ok(count) if (1..=MAX_CONCURRENCY).contains(&count) => Ok(count),
+        _ => Err(format!("Expected a number from 1 to {}: {}", MAX_CONCURRENCY, value)),
+    }
+}
+
+/// Parse a count, limit or number of seconds, a whole number from 0.
+fn parse_count(value: &str) -> Result<u64, String> {
+    value.trim().parse().map_err(|_| format!("Expected a whole number: {}", value))
+}
+
+/// Check that `--parallel-items` times `--chunks` stays within `MAX_CONCURRENCY`,
+/// as every item downloading in parallel can open `--chunks` connections to
+/// the same host.
+fn check_connection_count(matches: &ArgMatches) -> Result<(), String> {
+    let parallel_items: usize = matches.value_of_t("parallel-items").unwrap_or_else(|e| e.exit());
+    let chunks: usize = matches.value_of_t("chunks").unwrap_or_else(|e| e.exit());
+    if parallel_items * chunks > MAX_CONCURRENCY {
+        return Err(format!(
+            "--parallel-items {} with --chunks {} could open {} connections at once, at most {} are allowed",
+            parallel_items,
+            chunks,
+            parallel_items * chunks,
+            MAX_CONCURRENCY
+        ));
+    }
+    Ok(())
+}
+
+/// Caps the average transfer rate of every stream that shares it.
+struct RateLimiter {
+    bytes_per_second: u64,
+    state: std::sync::Mutex<(Instant, u64)>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_second: u64) -> Self {
+        RateLimiter {
+            bytes_per_second: bytes_per_second.max(1),
+            state: std::sync::Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Account for `bytes` just transferred, sleeping until the average rate is back under the cap.
+    async fn consume(&self, bytes: u64) {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            let rate = self.bytes_per_second as f64;
+            // Forget allowance saved up while idle, beyond one second's worth
+            if Duration::from_secs_f64(state.1 as f64 / rate) + Duration::from_secs(1) < state.0.elapsed() {
+                *state = (Instant::now(), 0);
+            }
+            state.1 += bytes;
+            let due = Duration::from_secs_f64(state.1 as f64 / rate);
+            due.saturating_sub(state.0.elapsed())
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// The rate limits that apply to one file: the global cap and the per-file cap.
+/// A file is bound by whichever of the two is tighter.
+struct Throttle<'a> {
+    global: Option<&'a RateLimiter>,
+    file: Option<RateLimiter>,
+}
+
+impl Throttle<'_> {
+    async fn consume(&self, bytes: u64) {
+        for limiter in self.global.iter().copied().chain(self.file.as_ref()) {
+            limiter.consume(bytes).await;
+        }
+    }
+}
+
+/// Files smaller than this are always downloaded over a single connection.
+const CHUNKED_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Progress bar template key for a size, in the units chosen with `--units`.
+fn size_key(key: &str) -> String {
+    if SI_UNITS.load(Ordering::Relaxed) {
+        format!("decimal_{}", key)
+    } else {
+        key.to_string()
+    }
+}
+
+/// Width of the last `--simple-progress` line, so a shorter one can blank its tail.
+static SIMPLE_LINE_WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Replace the `--simple-progress` line with `line`.
+fn draw_simple_line(line: &str) {
+    let width = line.chars().count();
+    let padding = SIMPLE_LINE_WIDTH.swap(width, Ordering::Relaxed).saturating_sub(width);
+    let text = format!("\r{}{}", line, " ".repeat(padding));
+    if MACHINE_OUTPUT.load(Ordering::Relaxed) {
+        eprint!("{}", text);
+        let _ = std::io::stderr().flush();
+    } else {
+        print!("{}", text);
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// End the `--simple-progress` line, leaving it on screen.
+fn finish_simple_line() {
+    if SIMPLE_LINE_WIDTH.swap(0, Ordering::Relaxed) > 0 {
+        if MACHINE_OUTPUT.load(Ordering::Relaxed) {
+            eprintln!();
+        } else {
+            println!();
+        }
+    }
+}
+
+/// Draw target for `--simple-progress` that rewrites one line with `\r` and no
+/// cursor movement, so it also works in CI logs that are not terminals.
+#[derive(Debug)]
+struct SimpleLine;
+
+impl indicatif::TermLike for SimpleLine {
+    fn width(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn move_cursor_up(&self, _n: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _n: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _n: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _n: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> std::io::Result<()> {
+        self.write_str(s)
+    }
+
+    fn write_str(&self, s: &str) -> std::io::Result<()> {
+        // Padding to the terminal width is handled by draw_simple_line
+        if !s.trim().is_empty() {
+            draw_simple_line(s);
+        }
+        Ok(())
+    }
+
+    fn clear_line(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `--simple-progress` line: plain text without box drawing, emoji or colors.
+fn simple_progress_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(format!("{{msg}}{{percent}}% of {{{}}} at {{{}}}, ETA {{eta}}", size_key("total_bytes"), size_key("bytes_per_sec")).as_str())
+        .expect("REASON")
+}
+
+fn progress_bar(total: u64, download_action: &str) -> ProgressBar {
+    // --summary-only hides the --simple-progress line too
+    if SUMMARY_ONLY.load(Ordering::Relaxed) {
+        return ProgressBar::hidden();
+    }
+    if SIMPLE_PROGRESS.load(Ordering::Relaxed) {
+        let pb = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::term_like_with_hz(Box::new(SimpleLine), 2));
+        pb.set_style(simple_progress_style());
+        return pb;
+    }
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(format!("{{prefix}}{}{{elapsed_precise}}     {{bar:40.green/green}} {{{}}}/{{{}}} (ETA: {{eta}})", download_action, size_key("bytes"), size_key("total_bytes")).as_str()).expect("REASON")
+            .progress_chars("▓▒░"),
+    );
+    match PARALLEL_PROGRESS.get() {
+        Some(bars) => {
+            pb.set_prefix(ITEM_PREFIX.try_with(String::clone).unwrap_or_default());
+            bars.add(pb)
+        }
+        None => pb,
+    }
+}
+
+/// Whether the server advertises support for byte range requests for `url`.
+async fn supports_ranges(client: &Client, url: &reqwest::Url, read_timeout: Option<Duration>) -> bool {
+    match send(client.head(url.clone()), read_timeout).await {
+        Ok(response) if response.status().is_success() => response
+            .headers()
+            .get(reqwest::header::ACCEPT_RANGES)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.eq_ignore_ascii_case("bytes"))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Download the whole item as one zip built by archive.org, for `--as-zip`.
+/// The zip is requested from the `/compress/` endpoint on the host serving
+/// `item_url`. Returns the path of the zip in `output_dir`.
+///
+/// The zip is generated on the fly, so it has no checksum and cannot be
+/// resumed; an interrupted download starts again from the beginning.
+async fn download_item_zip(
+    ctx: &Context<'_>,
+    item_url: &reqwest::Url,
+    identifier: &str,
+    output_dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let url = item_url.join(&format!("/compress/{}", identifier))?;
+    let zip_path = output_dir.join(format!("{}.zip", identifier));
+    let part_path = part_path(&zip_path);
+    ensure_parent_dir(&part_path)?;
+    let throttle = Throttle {
+        global: ctx.max_rate.as_ref(),
+        file: None,
+    };
+    let pb = progress_bar(0, "╰╼ Downloading  ");
+    let mut position = 0;
+    let result = download_stream(ctx, &url, &part_path, &mut position, &pb, &throttle, Duration::ZERO).await;
+    pb.finish_and_clear();
+    if let Err(e) = result {
+        // A request that failed before any data arrived leaves no part file
+        if let Err(remove_error) = fs::remove_file(&part_path) {
+            if remove_error.kind() != std::io::ErrorKind::NotFound {
+                status!("├╼ Could not remove {}: {}", part_path.display(), remove_error);
+            }
+        }
+        return Err(e);
+    }
+    fs::rename(&part_path, &zip_path)?;
+    status!("├╼ Downloaded   {}", format_size(position));
+    Ok(zip_path)
+}
+
+/// Fill in sizes missing from the metadata with the Content-Length of a HEAD
+/// request, running up to `--metadata-jobs` requests at once. Returns how many
+/// sizes were found.
+async fn preflight_sizes(ctx: &Context<'_>, item_url: &reqwest::Url, files: &mut [XmlFile]) -> usize {
+    use futures::StreamExt;
+
+    let jobs: usize = ctx.matches.value_of_t("metadata-jobs").unwrap_or_else(|e| e.exit());
+    let sizes: Vec<(usize, Option<u64>)> = futures::stream::iter(files.iter().enumerate().filter(|(_, file)| file.size.is_none()))
+        .map(|(index, file)| {
+            let url = construct_download_url(item_url, &file.name);
+            async move {
+                let Ok(url) = url else { return (index, None) };
+                let size = match ctx.client.head(url).send().await {
+                    Ok(response) if response.status().is_success() => response
+                        .headers()
+                        .get(reqwest::header::CONTENT_LENGTH)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok()),
+                    _ => None,
+                };
+                (index, size)
+            }
+        })
+        .buffer_unordered(jobs.max(1))
+        .collect()
+        .await;
+
+    let mut found = 0;
+    for (index, size) in sizes {
+        if size.is_some() {
+            files[index].size = size;
+            found += 1;
+        }
+    }
+    found
+}
+
+/// Where the byte ranges of a chunked download are joined, next to its partial download.
+fn chunks_path(part_path: &Path) -> PathBuf {
+    let mut path = part_path.as_os_str().to_owned();
+    path.push(".chunks");
+    PathBuf::from(path)
+}
+
+/// Download `size` bytes from `url` into `file_path` as `chunks` concurrent byte
+/// ranges, each written at its own offset. The caller verifies the joined file.
+///
+/// The ranges finish in any order, so they are written to a file of their own
+/// that only replaces `file_path` once every range is complete. `file_path`
+/// therefore never holds gaps that a later run could mistake for data.
+async fn download_chunked(
+    ctx: &Context<'_>,
+    url: &reqwest::Url,
+    file_path: &Path,
+    size: u64,
+    chunks: u64,
+    pb: &ProgressBar,
+    throttle: &Throttle<'_>,
+) -> Result<(), Box<dyn Error>> {
+    let joined_path = &chunks_path(file_path);
+    fs::File::create(joined_path)?.set_len(size)?;
+
+    let chunk_size = size.div_ceil(chunks);
+    let ranges = (0..chunks)
+        .map(|index| (index * chunk_size, ((index + 1) * chunk_size).min(size)))
+        .filter(|(start, end)| start < end);
+
+    let joined = futures::future::try_join_all(ranges.map(|(start, end)| async move {
+        let mut part = std::fs::OpenOptions::new().write(true).open(joined_path)?;
+        part.seek(std::io::SeekFrom::Start(start))?;
+
+        let range_header = format!("bytes={}-{}", start, end - 1);
+        let request = ctx.download_client.get(url.clone()).header(reqwest::header::RANGE, range_header);
+        let mut response = check_response(send(request, ctx.client_config.read_timeout).await?)?;
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err::<(), Box<dyn Error>>("Server ignored the requested byte range".into());
+        }
+        while let Some(data) = next_chunk(&mut response, ctx.client_config.read_timeout).await? {
+            ctx.check_deadline()?;
+            part.write_all(&data)?;
+            pb.inc(data.len() as u64);
+            throttle.consume(data.len() as u64).await;
+        }
+        Ok(())
+    }))
+    .await;
+    if let Err(e) = joined {
+        let _ = fs::remove_file(joined_path);
+        return Err(e);
+    }
+    fs::rename(joined_path, file_path)?;
+    Ok(())
+}
+
+/// Stream a file into `file_path` from byte `position` onwards. `position` follows
+/// the bytes written so an interrupted transfer can be retried from where it stopped.
+async fn download_stream(
+    ctx: &Context<'_>,
+    url: &reqwest::Url,
+    file_path: &Path,
+    position: &mut u64,
+    pb: &ProgressBar,
+    throttle: &Throttle<'_>,
+    progress_interval: Duration,
+) -> Result<(), Box<dyn Error>> {
+    // Set the Range header to specify the starting offset
+    let range_header = format!("bytes={}-", position);
+    let mut headers = HeaderMap::new();
+    headers.insert(reqwest::header::RANGE, HeaderValue::from_str(&range_header)?);
+    let request = ctx.download_client.get(url.clone()).headers(headers);
+
+    let mut response = check_response(send(request, ctx.client_config.read_timeout).await?)?;
+    // A server that ignores the range sends the whole file, which is written from the start
+    if *position > 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        *position = 0;
+    }
+
+    // Anything beyond `position` is not known to be good and is written again
+    let mut download = fs::OpenOptions::new().create(true).write(true).truncate(false).open(file_path)?;
+    download.set_len(*position)?;
+    download.seek(std::io::SeekFrom::Start(*position))?;
+
+    // Get the content length from the response headers
+    pb.set_length(response.content_length().unwrap_or(0) + *position);
+    pb.set_position(*position);
+
+    // Download the remaining chunks and update the progress bar
+    let mut last_update = Instant::now();
+    while let Some(chunk) = next_chunk(&mut response, ctx.client_config.read_timeout).await? {
+        ctx.check_deadline()?;
+        download.write_all(&chunk)?;
+        *position += chunk.len() as u64;
+        throttle.consume(chunk.len() as u64).await;
+        // Coalesce updates so slow consoles and logs are not flooded
+        if last_update.elapsed() >= progress_interval {
+            pb.set_position(*position);
+            last_update = Instant::now();
+        }
+    }
+    pb.set_position(*position);
+    Ok(())
+}
+
+async fn calculate_md5(file_path: &Path) -> Result<String, std::io::Error> {
+    hash_in_background(file_path, HashAlgorithm::Md5).await
+}
+
+/// Hash downloaded files again for `--final-verify`, several at a time, and
+/// return the name, failure kind and reason of each that is missing or no
+/// longer matches its checksum.
+async fn recheck_files(checks: Vec<(String, PathBuf, HashAlgorithm, String)>) -> Vec<(String, &'static str, String)> {
+    use futures::StreamExt;
+
+    let jobs = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+    futures::stream::iter(checks)
+        .map(|(name, path, algorithm, expected)| async move {
+            let hashed = tokio::task::spawn_blocking(move || calculate_hash(&path, algorithm)).await;
+            match hashed {
+                Ok(Ok(actual)) if actual == expected => None,
+                Ok(Ok(actual)) => {
+                    Some((name, "checksum_mismatch", format!("expected {:?} {}, got {}", algorithm, expected, actual)))
+                }
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => Some((name, "missing_file", "missing".to_string())),
+                Ok(Err(e)) => Some((name, "io", e.to_string())),
+                Err(e) => Some((name, "other", e.to_string())),
+            }
+        })
+        .buffer_unordered(jobs)
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await
+}
+
+/// Hash algorithms found in coreutils style checksum files.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// The algorithm that produces a hex digest of this many characters.
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(HashAlgorithm::Md5),
+            40 => Some(HashAlgorithm::Sha1),
+            64 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// Size of the buffer files are read through while hashing, from `--hash-buffer`.
+static HASH_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024);
+
+/// Feed a file to `hasher` one `buffer_size` piece at a time, so memory use
+/// stays the same however large the file is.
+fn hash_file(file_path: &Path, buffer_size: usize, hasher: &mut impl Write) -> Result<(), std::io::Error> {
+    let mut file = fs::File::open(file_path)?;
+    let mut buffer = vec![0; buffer_size.max(1)];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => hasher.write_all(&buffer[..read])?,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Hash `file_path` on the blocking thread pool, so a long hash neither holds up
+/// other transfers nor keeps `--max-duration` from stopping the run.
+async fn hash_in_background(file_path: &Path, algorithm: HashAlgorithm) -> Result<String, std::io::Error> {
+    let file_path = file_path.to_path_buf();
+    tokio::task::spawn_blocking(move || calculate_hash(&file_path, algorithm)).await?
+}
+
+/// Hex digest of a file.
+fn calculate_hash(file_path: &Path, algorithm: HashAlgorithm) -> Result<String, std::io::Error> {
+    use sha2::Digest;
+
+    let buffer_size = HASH_BUFFER_SIZE.load(Ordering::Relaxed);
+    Ok(match algorithm {
+        HashAlgorithm::Md5 => {
+            let mut context = md5::Context::new();
+            hash_file(file_path, buffer_size, &mut context)?;
+            format!("{:x}", context.compute())
+        }
+        HashAlgorithm::Sha1 => {
+            let mut hasher = sha1::Sha1::new();
+            hash_file(file_path, buffer_size, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+        HashAlgorithm::Sha256 => {
+            let mut hasher = sha2::Sha256::new();
+            hash_file(file_path, buffer_size, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+    })
+}
+
+/// A checksum for one file from a `--checksum-source`.
+#[derive(Debug)]
+struct ExternalChecksum {
+    algorithm: HashAlgorithm,
+    hex: String,
+}
+
+/// Whether a file in an item looks like a MD5SUMS, SHA1SUMS or SHA256SUMS file.
+fn is_checksum_file(name: &str) -> bool {
+    let base = name.rsplit('/').next().unwrap_or(name).to_ascii_lowercase();
+    let base = base.strip_suffix(".txt").unwrap_or(&base);
+    matches!(base, "md5sums" | "sha1sums" | "sha256sums")
+}
+
+/// Parse `<hex>  <name>` lines as written by `md5sum`, `sha1sum` and `sha256sum`,
+/// with `prefix` added to each name to make it relative to the item.
+fn parse_checksum_file(text: &str, prefix: &str) -> std::collections::HashMap<String, ExternalChecksum> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (hex, name) = line.split_once(char::is_whitespace)?;
+            let algorithm = HashAlgorithm::from_hex_len(hex.len()).filter(|_| hex.chars().all(|c| c.is_ascii_hexdigit()))?;
+            // A leading * marks binary mode
+            let name = name.trim_start().trim_start_matches('*').trim_start_matches("./");
+            let checksum = ExternalChecksum { algorithm, hex: hex.to_ascii_lowercase() };
+            Some((format!("{}{}", prefix, name), checksum))
+        })
+        .collect()
+}
+
+/// Checksums from `--checksum-source`, either a local checksum file with names
+/// relative to the item, or with `auto` every checksum file the item contains.
+async fn external_checksums(
+    ctx: &Context<'_>,
+    source: &str,
+    item_url: &reqwest::Url,
+    files: &[XmlFile],
+) -> Result<std::collections::HashMap<String, ExternalChecksum>, Box<dyn Error>> {
+    if source != "auto" {
+        let text = fs::read_to_string(source).map_err(|e| format!("Failed to read checksum file {}: {}", source, e))?;
+        return Ok(parse_checksum_file(&text, ""));
+    }
+    let mut checksums = std::collections::HashMap::new();
+    for sums in files.iter().filter(|file| is_checksum_file(&file.name)) {
+        let url = construct_download_url(item_url, &sums.name)?;
+        let text = check_response(ctx.client.get(url).send().await?)?.text().await?;
+        let prefix = sums.name.rsplit_once('/').map(|(dir, _)| format!("{}/", dir)).unwrap_or_default();
+        checksums.extend(parse_checksum_file(&text, &prefix));
+    }
+    Ok(checksums)
+}
+
+/// What to do when a file that is about to be downloaded already exists.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum OverwritePolicy {
+    /// Always download the file again from scratch.
+    Always,
+    /// Never touch an existing file.
+    Never,
+    /// Keep matching files, resume short files and replace anything else.
+    IfDifferent,
+}
+
+impl std::str::FromStr for OverwritePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(OverwritePolicy::Always),
+            "never" => Ok(OverwritePolicy::Never),
+            "if-different" => Ok(OverwritePolicy::IfDifferent),
+            _ => Err(format!("unknown overwrite policy: {}", s)),
+        }
+    }
+}
+
+/// The action chosen for an existing local file.
+#[derive(Debug, PartialEq)]
+enum ExistingFileAction {
+    Keep,
+    Resume,
+    Replace,
+}
+
+/// Decide what to do with an existing local copy of `file` under `policy`.
+///
+/// Files smaller than `verify_min_size` are compared by size alone.
+async fn existing_file_action(
+    file: &XmlFile,
+    local_path: &Path,
+    policy: OverwritePolicy,
+    verify_min_size: u64,
+) -> Result<ExistingFileAction, Box<dyn Error>> {
+    match policy {
+        OverwritePolicy::Always => return Ok(ExistingFileAction::Replace),
+        OverwritePolicy::Never => return Ok(ExistingFileAction::Keep),
+        OverwritePolicy::IfDifferent => {}
+    }
+
+    let local_size = fs::metadata(local_path)?.len();
+    match file.size {
+        // A short file is most likely an interrupted download
+        Some(expected) if local_size < expected => return Ok(ExistingFileAction::Resume),
+        // Appending to a file that is already too big can never succeed
+        Some(expected) if local_size > expected => return Ok(ExistingFileAction::Replace),
+        Some(expected) if expected < verify_min_size => return Ok(ExistingFileAction::Keep),
+        _ => {}
+    }
+
+    let Some(expected_md5) = &file.md5 else {
+        // Without a checksum a matching size is the best evidence available
+        return Ok(if file.size.is_some() { ExistingFileAction::Keep } else { ExistingFileAction::Replace });
+    };
+    let local_md5 = calculate_md5(local_path).await.map_err(|e| format!("Failed to calculate MD5 hash: {}", e))?;
+    if *expected_md5 == local_md5 {
+        Ok(ExistingFileAction::Keep)
+    } else if file.size.is_some() {
+        // Same size but different content, resuming would request an empty range
+        Ok(ExistingFileAction::Replace)
+    } else {
+        Ok(ExistingFileAction::Resume)
+    }
+}
+
+/// Archive formats that `--decompress` can extract.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ArchiveFormat {
+    Zip,
+    Gzip,
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "gzip" => Ok(ArchiveFormat::Gzip),
+            _ => Err(format!("unknown archive format: {}", s)),
+        }
+    }
+}
+
+/// The archive format of a downloaded file, going by its extension and
+/// confirmed by its leading magic bytes.
+fn detect_archive(file_path: &Path) -> Result<Option<ArchiveFormat>, std::io::Error> {
+    let extension = file_path.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    let (format, expected): (ArchiveFormat, &[u8]) = match extension.as_str() {
+        "zip" => (ArchiveFormat::Zip, b"PK\x03\x04"),
+        "gz" => (ArchiveFormat::Gzip, b"\x1f\x8b"),
+        _ => return Ok(None),
+    };
+    let mut magic = vec![0u8; expected.len()];
+    match fs::File::open(file_path)?.read_exact(&mut magic) {
+        Ok(()) => Ok((magic == expected).then_some(format)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where `--decompress` extracts an archive: next to it, named like it without
+/// its extension. A zip file becomes a directory, a gzip file the file it holds.
+fn archive_destination(file_path: &Path) -> PathBuf {
+    file_path.with_extension("")
+}
+
+/// Extract a downloaded archive next to it, returning where it went and the
+/// number of files written.
+///
+/// The archive is extracted to a temporary path that only takes the final name
+/// once it is complete, so a failed extraction never leaves half of it behind.
+fn extract_archive(file_path: &Path, format: ArchiveFormat) -> Result<(PathBuf, usize), Box<dyn Error>> {
+    let destination = archive_destination(file_path);
+    let temporary = part_path(&destination);
+    remove_extracted(&temporary);
+    let extracted = (|| -> Result<usize, Box<dyn Error>> {
+        let extracted = match format {
+            ArchiveFormat::Zip => extract_zip(file_path, &temporary)?,
+            ArchiveFormat::Gzip => {
+                let mut decoder = flate2::read::MultiGzDecoder::new(std::io::BufReader::new(fs::File::open(file_path)?));
+                std::io::copy(&mut decoder, &mut fs::File::create(&temporary)?)?;
+                1
+            }
+        };
+        // A directory from an earlier extraction is replaced, a rename cannot do that
+        if format == ArchiveFormat::Zip && destination.is_dir() {
+            fs::remove_dir_all(&destination)?;
+        }
+        fs::rename(&temporary, &destination)?;
+        Ok(extracted)
+    })();
+    match extracted {
+        Ok(extracted) => Ok((destination, extracted)),
+        Err(e) => {
+            remove_extracted(&temporary);
+            Err(e)
+        }
+    }
+}
+
+/// Remove what an extraction left at `path`, a directory or a file, if anything.
+fn remove_extracted(path: &Path) {
+    let _ = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+}
+
+/// Extract an archive on the blocking thread pool, like [`hash_in_background`],
+/// so a large archive does not hold up the transfers running alongside it.
+///
+/// Archives whose destination is, or holds, one of `item_paths` are refused, so
+/// extraction never writes over a file of the item.
+async fn extract_in_background(file_path: &Path, format: ArchiveFormat, item_paths: &[PathBuf]) -> Result<(PathBuf, usize), String> {
+    let destination = archive_destination(file_path);
+    if item_paths.iter().any(|path| path.starts_with(&destination)) {
+        return Err(format!("{} is part of the item, not extracted", destination.display()));
+    }
+    let file_path = file_path.to_path_buf();
+    let prefix = ITEM_PREFIX.try_with(String::clone).unwrap_or_default();
+    tokio::task::spawn_blocking(move || {
+        ITEM_PREFIX.sync_scope(prefix, || extract_archive(&file_path, format).map_err(|e| e.to_string()))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Keep or, with `--remove-compressed`, delete an archive that was extracted
+/// to `destination`, recording which in the event log. Returns whether the
+/// archive was removed.
+///
+/// Only called once [`extract_archive`] succeeded, which reads every entry
+/// through its CRC check, so a failed extraction never deletes the only copy.
+fn finish_extraction(ctx: &Context<'_>, identifier: &str, name: &str, archive: &Path, destination: &Path, remove: bool) -> bool {
+    let removed = remove
+        && match fs::remove_file(archive) {
+            Ok(()) => true,
+            Err(e) => {
+                status!("├╼ Could not remove {}: {}", archive.display(), e);
+                false
+            }
+        };
+    if removed {
+        status!("├╼ Removed      🗑️ {}", name);
+    }
+    ctx.event(
+        "file_extracted",
+        serde_json::json!({
+            "identifier": identifier,
+            "name": name,
+            "destination": destination,
+            "compressed": if removed { "removed" } else { "kept" },
+        }),
+    );
+    removed
+}
+
+/// Extract a zip file into `destination`, returning the number of files written.
+///
+/// Entries that would land outside that directory are skipped and every path
+/// component is sanitized, so a hostile archive cannot write elsewhere.
+fn extract_zip(file_path: &Path, destination: &Path) -> Result<usize, Box<dyn Error>> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(file_path)?)?;
+    fs::create_dir_all(destination)?;
+    let mut extracted = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let Some(name) = entry.enclosed_name() else {
+            status!("├╼ Unsafe path: {}", entry.name());
+            continue;
+        };
+        let relative: PathBuf = name
+            .components()
+            .filter_map(|component| match component {
+                std::path::Component::Normal(part) => Some(sanitize_path_component(&part.to_string_lossy())),
+                _ => None,
+            })
+            .collect();
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        let target = destination.join(relative);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        std::io::copy(&mut entry, &mut fs::File::create(&target)?)?;
+        extracted += 1;
+    }
+    Ok(extracted)
+}
+
+/// How a `--dry-run` would treat each selected file, compared with the output directory.
+#[derive(Serialize, Debug, Default)]
+struct DryRunReport {
+    /// Files with no local copy.
+    new: Vec<String>,
+    /// Files whose local copy would be kept.
+    skip: Vec<String>,
+    /// Files whose partial download, or short local copy, would be carried on.
+    resume: Vec<String>,
+    /// Files whose local copy would be replaced.
+    redownload: Vec<String>,
+    /// Files whose name would place them outside the output directory.
+    excluded: Vec<String>,
+}
+
+/// Classify `files` against their local copies and partial downloads in
+/// `output_dir` using the same checks as a real download under `policy`.
+async fn dry_run_report(
+    files: &[XmlFile],
+    output_dir: &Path,
+    policy: OverwritePolicy,
+    verify_min_size: u64,
+) -> Result<DryRunReport, Box<dyn Error>> {
+    let mut report = DryRunReport::default();
+    for file in files {
+        let Ok(local_path) = confined_path(output_dir, &file.name) else {
+            report.excluded.push(file.name.clone());
+            continue;
+        };
+        let names = if local_path.exists() {
+            match existing_file_action(file, &local_path, policy, verify_min_size).await? {
+                ExistingFileAction::Keep => &mut report.skip,
+                ExistingFileAction::Resume => &mut report.resume,
+                ExistingFileAction::Replace => &mut report.redownload,
+            }
+        } else {
+            // Partial downloads are discarded when overwriting or when too big
+            match fs::metadata(part_path(&local_path)) {
+                Ok(_) if policy == OverwritePolicy::Always => &mut report.new,
+                Ok(part) if file.size.is_none_or(|size| part.len() <= size) => &mut report.resume,
+                _ => &mut report.new,
+            }
+        };
+        names.push(file.name.clone());
+    }
+    Ok(report)
+}
+
+fn print_dry_run_report(report: &DryRunReport, verbose: bool) {
+    summary!(" ");
+    let total = report.new.len() + report.skip.len() + report.resume.len() + report.redownload.len() + report.excluded.len();
+    summary!("🧪 Dry run      {} files", total);
+    let sections = [
+        ("├╼ New         ", "│  ├╼", &report.new),
+        ("├╼ Skip        ", "│  ├╼", &report.skip),
+        ("├╼ Resume      ", "│  ├╼", &report.resume),
+        ("├╼ Re-download ", "│  ├╼", &report.redownload),
+        ("╰╼ Excluded    ", "   ├╼", &report.excluded),
+    ];
+    for (label, branch, names) in sections {
+        summary!("{} {}", label, names.len());
+        if verbose {
+            for name in names {
+                summary!("{} {}", branch, name);
+            }
+        }
+    }
+}
+
+/// Where a file is written while it downloads, next to its final location.
+fn part_path(local_path: &Path) -> PathBuf {
+    let mut path = local_path.as_os_str().to_owned();
+    path.push(".part");
+    PathBuf::from(path)
+}
+
+/// Partial downloads, and the joined ranges of chunked downloads, in the item's
+/// directories that belong to none of its files.
+fn orphaned_parts(output_dir: &Path, files: &[XmlFile]) -> Vec<PathBuf> {
+    // Names that would leave the output directory are refused, their directories are never scanned
+    let local_paths: Vec<PathBuf> = files.iter().filter_map(|file| confined_path(output_dir, &file.name).ok()).collect();
+    let known: std::collections::HashSet<PathBuf> = local_paths.iter().map(|path| part_path(path)).collect();
+    let directories: std::collections::BTreeSet<PathBuf> = known
+        .iter()
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect();
+    let mut orphans: Vec<PathBuf> = directories
+        .iter()
+        .filter_map(|directory| fs::read_dir(directory).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "part" || ext == "chunks") && path.is_file() && !known.contains(path))
+        .filter(|path| !local_paths.contains(path))
+        .collect();
+    orphans.sort();
+    orphans
+}
+
+/// Files that would be written over by another of `files`, as the index of each
+/// paired with the index of the earlier file it clashes with.
+///
+/// A file clashes with one named like it plus `.part` or `.part.chunks`, where
+/// the other is written while it downloads. Names that differ only in case clash
+/// on Windows and macOS, whose filesystems usually ignore case.
+fn name_clashes(files: &[XmlFile]) -> Vec<(usize, usize)> {
+    let key = |name: &str| if cfg!(any(windows, target_os = "macos")) { name.to_lowercase() } else { name.to_string() };
+    let mut seen: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    let mut clashes = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        let name = key(&file.name);
+        let temporary = [".part", ".part.chunks"];
+        let clash = seen
+            .get(&name)
+            .or_else(|| temporary.iter().find_map(|suffix| seen.get(name.strip_suffix(suffix)?)))
+            .or_else(|| temporary.iter().find_map(|suffix| seen.get(&format!("{}{}", name, suffix))));
+        match clash {
+            Some(&earlier) => clashes.push((index, earlier)),
+            None => {
+                seen.insert(name, index);
+            }
+        }
+    }
+    clashes
+}
+
+/// Free space available to this user on the filesystem that holds `path`, or
+/// will hold it once created. `None` where the platform cannot report it.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let existing = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs only writes to the zeroed struct it is given
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Bytes still to download for `file`, less whatever is already on disk.
+fn remaining_bytes(file: &XmlFile, output_dir: &Path) -> u64 {
+    let Ok(local_path) = confined_path(output_dir, &file.name) else {
+        return 0;
+    };
+    let present = [part_path(&local_path), local_path]
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .max()
+        .unwrap_or(0);
+    file.size.unwrap_or(0).saturating_sub(present)
+}
+
+/// Create the local directory for `file_path` if it includes a path that does not exist.
+fn ensure_parent_dir(file_path: &Path) -> Result<(), std::io::Error> {
+    if let Some(path) = file_path.parent() {
+        if path.file_name().is_some() && !path.exists() {
+            fs::create_dir_all(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a reference copy exists and has the size published in the metadata.
+fn reference_matches(reference_path: &Path, file: &XmlFile) -> bool {
+    match fs::metadata(reference_path) {
+        Ok(metadata) if metadata.is_file() => file.size.is_none_or(|size| size == metadata.len()),
+        _ => false,
+    }
+}
+
+/// Hardlink `source` to `destination`, copying instead when linking is not possible.
+fn link_or_copy(source: &Path, destination: &Path) -> Result<(), std::io::Error> {
+    if fs::hard_link(source, destination).is_err() {
+        fs::copy(source, destination)?;
+    }
+    Ok(())
+}
+
+/// Modification times further ahead of the local clock than this are treated as bogus.
+const MTIME_FUTURE_TOLERANCE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Parse a metadata mtime, Unix seconds with any fraction ignored.
+fn parse_mtime(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let seconds = value.split_once('.').map_or(value, |(seconds, _)| seconds);
+    seconds.parse().map_err(|_| format!("invalid mtime {:?}", value))
+}
+
+/// The time to give a downloaded file from its metadata mtime. A time far in
+/// the future, from clock skew on the uploader's side, is clamped to now.
+/// `Err` explains why the file keeps its own mtime instead.
+fn file_mtime(mtime: &str, now: SystemTime) -> Result<(SystemTime, Option<String>), String> {
+    let mtime = UNIX_EPOCH + Duration::from_secs(parse_mtime(mtime)?);
+    if mtime > now + MTIME_FUTURE_TOLERANCE {
+        let ahead = mtime.duration_since(now).unwrap_or_default();
+        return Ok((now, Some(format!("mtime is {} in the future, clamped to now", HumanDuration(ahead)))));
+    }
+    Ok((mtime, None))
+}
+
+/// Whether other hardlinks share this file's contents, as `--link-existing` and
+/// `--link-dupes` can leave. Only Unix can tell, elsewhere this is always `false`.
+#[cfg(unix)]
+fn is_hardlinked(file_path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(file_path).is_ok_and(|metadata| metadata.nlink() > 1)
+}
+
+#[cfg(not(unix))]
+fn is_hardlinked(_file_path: &Path) -> bool {
+    false
+}
+
+/// Give a hardlinked file contents of its own, so that changing it leaves the
+/// files it is linked to alone.
+fn break_hardlink(file_path: &Path) -> Result<(), std::io::Error> {
+    if !is_hardlinked(file_path) {
+        return Ok(());
+    }
+    let mut copy = file_path.as_os_str().to_owned();
+    copy.push(".unlink");
+    let copy = PathBuf::from(copy);
+    fs::copy(file_path, &copy)?;
+    fs::rename(&copy, file_path)
+}
+
+/// Set or clear the read-only flag (Unix write bits, Windows readonly attribute).
+///
+/// A hardlinked file is copied first, since the flag belongs to the contents
+/// every link shares, and clearing it is how a file is readied for writing.
+fn set_read_only(file_path: &Path, read_only: bool) -> Result<(), std::io::Error> {
+    let mut permissions = fs::metadata(file_path)?.permissions();
+    if permissions.readonly() != read_only {
+        break_hardlink(file_path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Restore owner write access only, rather than making the file world writable
+            let mode = permissions.mode();
+            permissions.set_mode(if read_only { mode & !0o222 } else { mode | 0o200 });
+        }
+        #[cfg(not(unix))]
+        permissions.set_readonly(read_only);
+        fs::set_permissions(file_path, permissions)?;
+    }
+    Ok(())
+}
+
+/// The `--log-hash-errors` file and the files already written to it.
+struct HashErrorLog {
+    file: fs::File,
+    logged: std::collections::HashSet<(String, String)>,
+}
+
+/// Settings and HTTP clients shared by every item downloaded in a run.
+struct Context<'a> {
+    matches: &'a ArgMatches,
+    client_config: ClientConfig,
+    client: Client,
+    download_client: Client,
+    /// Caps the combined download rate of the whole run.
+    max_rate: Option<RateLimiter>,
+    /// Retries taken so far across every file and item of the run.
+    total_retries: AtomicU64,
+    /// The file selection filters, applied to every item.
+    filters: FilterOptions,
+    /// Files to download from `--plan` or `--retry-file`, used instead of the filters.
+    plans: Vec<DownloadPlan>,
+    /// Files that failed during the run, written out by `--failures-out`.
+    failures: std::sync::Mutex<Vec<FailedFile>>,
+    /// Append-only JSON lines log of the run's events, from `--event-log`.
+    event_log: Option<std::sync::Mutex<fs::File>>,
+    /// Checksum mismatches from `--log-hash-errors`.
+    hash_error_log: Option<std::sync::Mutex<HashErrorLog>>,
+    /// When the run started and how long it may take, from `--max-duration`.
+    started: Instant,
+    max_duration: Option<Duration>,
+}
+
+impl<'a> Context<'a> {
+    /// Read the run's settings from `matches`, open its log files and build its clients.
+    fn new(matches: &'a ArgMatches) -> Result<Self, Box<dyn Error>> {
+        // Disk full and permission problems keep their own exit codes, anything else says which log failed
+        let open_error = |e: std::io::Error, log: &str, path: &str| match file_system_error(e, Path::new(path)) {
+            e if e.is::<IaGetError>() => e,
+            e => format!("Failed to open {} {}: {}", log, path, e).into(),
+        };
+        let client_config = ClientConfig::from_matches(matches);
+        let max_rate = matches.value_of("max-rate").map(parse_size).transpose()?;
+        let max_rate_per_file = matches.value_of("max-rate-per-file").map(parse_size).transpose()?;
+        if matches.is_present("verbose") && (max_rate.is_some() || max_rate_per_file.is_some()) {
+            let describe = |rate: Option<u64>| match rate {
+                Some(rate) => format!("{}/s", format_size(rate)),
+                None => "unlimited".to_string(),
+            };
+            status!("Rate limits: {} overall, {} per file", describe(max_rate), describe(max_rate_per_file));
+        }
+        Ok(Context {
+            max_rate: max_rate.map(RateLimiter::new),
+            total_retries: AtomicU64::new(0),
+            filters: FilterOptions::from_matches(matches).unwrap_or_else(|e| {
+                report_error("Exiting", &*e, None);
+                process::exit(EXIT_INVALID_ARGS);
+            }),
+            plans: match (matches.value_of("plan"), matches.value_of("retry-file")) {
+                (Some(path), _) => DownloadPlan::read(path).map(|plan| vec![plan]),
+                (None, Some(path)) => retry_plans(path),
+                (None, None) => Ok(Vec::new()),
+            }
+            .unwrap_or_else(|e| {
+                report_error("Exiting", &*e, None);
+                process::exit(EXIT_INVALID_ARGS);
+            }),
+            failures: std::sync::Mutex::new(Vec::new()),
+            started: Instant::now(),
+            max_duration: matches.value_of("max-duration").map(parse_duration).transpose()?,
+            event_log: match matches.value_of("event-log") {
+                Some(path) => {
+                    let truncate = matches.is_present("truncate-event-log");
+                    let log = fs::OpenOptions::new()
+                        .create(true)
+                        .append(!truncate)
+                        .write(true)
+                        .truncate(truncate)
+                        .open(path)
+                        .map_err(|e| open_error(e, "event log", path))?;
+                    Some(std::sync::Mutex::new(log))
+                }
+                None => None,
+            },
+            hash_error_log: match matches.value_of("log-hash-errors") {
+                Some(path) => {
+                    let file = fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|e| open_error(e, "hash error log", path))?;
+                    Some(std::sync::Mutex::new(HashErrorLog { file, logged: std::collections::HashSet::new() }))
+                }
+                None => None,
+            },
+            client: client_config.metadata_client()?,
+            download_client: client_config.download_client()?,
+            client_config,
+            matches,
+        })
+    }
+
+    fn plan_for(&self, identifier: &str) -> Option<&DownloadPlan> {
+        self.plans.iter().find(|plan| plan.identifier == identifier)
+    }
+
+    fn record_failure(&self, identifier: &str, name: &str, kind: &str, message: String) {
+        self.event(
+            "file_failed",
+            serde_json::json!({ "identifier": identifier, "name": name, "kind": kind, "message": message }),
+        );
+        self.failures.lock().unwrap().push(FailedFile {
+            identifier: identifier.to_string(),
+            name: name.to_string(),
+            kind: kind.to_string(),
+            message,
+        });
+    }
+
+    /// Append a timestamped `event` record with `fields` to the `--event-log`, if there is one.
+    fn event(&self, event: &str, fields: serde_json::Value) {
+        let Some(log) = &self.event_log else {
+            return;
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default();
+        let mut record = serde_json::json!({ "timestamp": timestamp, "event": event });
+        if let (Some(record), serde_json::Value::Object(fields)) = (record.as_object_mut(), fields) {
+            record.extend(fields);
+        }
+        if let Err(e) = writeln!(log.lock().unwrap(), "{}", record) {
+            status!("Failed to write event log: {}", e);
+        }
+    }
+
+    /// Append a checksum mismatch to the `--log-hash-errors` file, once per file
+    /// however many times it is downloaded again.
+    fn log_hash_error(&self, identifier: &str, file: &XmlFile, expected: &str, actual: &str, url: &str) {
+        let Some(log) = &self.hash_error_log else {
+            return;
+        };
+        let mut log = log.lock().unwrap();
+        if !log.logged.insert((identifier.to_string(), file.name.clone())) {
+            return;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default();
+        let record = serde_json::json!({
+            "timestamp": timestamp,
+            "identifier": identifier,
+            "name": file.name,
+            "expected": expected,
+            "actual": actual,
+            "size": file.size,
+            "url": url,
+        });
+        if let Err(e) = writeln!(log.file, "{}", record) {
+            status!("Failed to write hash error log: {}", e);
+        }
+    }
+
+    /// Write the failed files for `--failures-out`, if requested, and exit.
+    fn exit(&self, code: i32) -> ! {
+        self.event("run_finished", serde_json::json!({ "exit_code": code }));
+        self.write_failures();
+        process::exit(code);
+    }
+
+    /// Write the files that failed so far to the `--failures-out` file, if one was given.
+    fn write_failures(&self) {
+        let Some(path) = self.matches.value_of("failures-out") else {
+            return;
+        };
+        let failures = self.failures.lock().unwrap();
+        let written = serde_json::to_string_pretty(&*failures)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        match written {
+            Ok(()) if !failures.is_empty() => status!("Failures list: {} ({} files)", path, failures.len()),
+            Ok(()) => {}
+            Err(e) => status!("Failed to write failures list {}: {}", path, e),
+        }
+    }
+
+    /// Fail once the run has taken longer than `--max-duration`.
+    fn check_deadline(&self) -> Result<(), IaGetError> {
+        match self.max_duration {
+            Some(limit) if self.started.elapsed() >= limit => Err(IaGetError::TimedOut { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `work`, abandoning it when the run reaches `--max-duration` whatever
+    /// it is waiting on, be it a response, a retry, the rate limit or a hash.
+    async fn within_deadline<T>(&self, work: impl std::future::Future<Output = Result<T, Box<dyn Error>>>) -> Result<T, Box<dyn Error>> {
+        match self.max_duration {
+            Some(limit) => tokio::time::timeout_at((self.started + limit).into(), work)
+                .await
+                .unwrap_or_else(|_| Err(IaGetError::TimedOut { limit }.into())),
+            None => work.await,
+        }
+    }
+
+    /// Take one retry from the run's budget, failing once `--max-total-retries` is spent.
+    fn take_retry(&self) -> Result<(), IaGetError> {
+        let limit: u64 = self.matches.value_of_t("max-total-retries").unwrap_or_else(|e| e.exit());
+        if limit > 0 && self.total_retries.load(Ordering::Relaxed) >= limit {
+            return Err(IaGetError::RetryBudgetExhausted { limit });
+        }
+        self.total_retries.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Whether a failed transfer is worth retrying.
+fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    if let Some(e) = error.downcast_ref::<IaGetError>() {
+        return e.is_transient();
+    }
+    error.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout() || e.is_connect() || e.is_request() || e.is_body())
+}
+
+/// The longest a 429's Retry-After may hold up a retry, so a misbehaving
+/// server cannot stall the run for hours.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait before retrying, honouring a 429's Retry-After up to `MAX_RETRY_AFTER`.
+fn retry_delay(error: &(dyn Error + 'static), attempt: u32) -> Duration {
+    match error.downcast_ref::<IaGetError>() {
+        Some(IaGetError::RateLimited { retry_after: Some(seconds) }) => Duration::from_secs(*seconds).min(MAX_RETRY_AFTER),
+        _ => Duration::from_secs(1 << attempt.min(5)),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResults {
+    response: SearchResponse,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    #[serde(rename = "numFound")]
+    num_found: usize,
+    docs: Vec<SearchDoc>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchDoc {
+    identifier: String,
+}
+
+/// Page through the archive.org search API collecting every identifier matching `query`.
+async fn search_identifiers(client: &Client, query: &str, limit: Option<usize>) -> Result<Vec<String>, Box<dyn Error>> {
+    const ROWS: usize = 500;
+    let mut identifiers = Vec::new();
+    let mut page = 1;
+    loop {
+        let url = reqwest::Url::parse_with_params(
+            "https://archive.org/advancedsearch.php",
+            &[
+                ("q", query),
+                ("fl[]", "identifier"),
+                ("sort[]", "identifier asc"),
+                ("rows", &ROWS.to_string()),
+                ("page", &page.to_string()),
+                ("output", "json"),
+            ],
+        )?;
+        let response = check_response(client.get(url).send().await?)?;
+        let results: SearchResults = serde_json::from_slice(&response.bytes().await?)?;
+        let returned = results.response.docs.len();
+        identifiers.extend(results.response.docs.into_iter().map(|doc| doc.identifier));
+
+        if let Some(limit) = limit {
+            if identifiers.len() >= limit {
+                identifiers.truncate(limit);
+                break;
+            }
+        }
+        if returned < ROWS || identifiers.len() >= results.response.num_found {
+            break;
+        }
+        page += 1;
+    }
+    Ok(identifiers)
+}
+
+/// What happened to the files of an item that was processed to the end.
+#[derive(Debug, Default)]
+struct ItemOutcome {
+    /// Files that were downloaded but failed verification.
+    failed_files: Vec<String>,
+    /// Files that could not be verified because the metadata has no checksum for them.
+    unverified_files: Vec<String>,
+    /// Files below `--verify-min-size` that were checked by size instead of checksum.
+    size_checked_files: Vec<String>,
+}
+
+/// The items found by one search query, without those an earlier query already found.
+#[derive(Serialize, Debug)]
+struct Search {
+    query: String,
+    identifiers: Vec<String>,
+}
+
+/// The entries of a list with one per line, skipping blank lines and `#` comments.
+fn list_entries(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Read search queries from a file, one per line.
+fn read_queries(path: &str) -> Result<Vec<String>, std::io::Error> {
+    Ok(list_entries(&fs::read_to_string(path)?))
+}
+
+/// Run each search query, dropping identifiers already found by an earlier
+/// query and stopping once `max_items` identifiers have been collected.
+async fn run_searches(
+    client: &Client,
+    queries: &[String],
+    limit: Option<usize>,
+    max_items: Option<usize>,
+) -> Result<Vec<Search>, Box<dyn Error>> {
+    let mut seen = std::collections::HashSet::new();
+    let mut searches = Vec::new();
+    for query in queries {
+        if max_items.is_some_and(|max| seen.len() >= max) {
+            break;
+        }
+        status!("Archive.org search: {}", query);
+        let found = search_identifiers(client, query, limit).await?;
+        let total = found.len();
+        let mut identifiers: Vec<String> = found.into_iter().filter(|identifier| seen.insert(identifier.clone())).collect();
+        if let Some(max) = max_items {
+            let over = seen.len().saturating_sub(max);
+            identifiers.truncate(identifiers.len() - over);
+        }
+        status!("╰╼ Items found:   {} ({} new)", total, identifiers.len());
+        searches.push(Search { query: query.clone(), identifiers });
+    }
+    Ok(searches)
+}
+
+/// Drop items that name the same identifier as an earlier item, whether given as
+/// a bare identifier or a details or download URL. The first occurrence is kept.
+/// Items that are not valid identifiers are kept so their error is reported.
+fn merge_duplicate_items<'a>(items: impl IntoIterator<Item = &'a String>) -> (Vec<&'a String>, usize) {
+    let mut seen = std::collections::HashSet::new();
+    let mut merged = 0;
+    let mut unique = Vec::new();
+    for item in items {
+        let identifier = normalize_archive_identifier(item).unwrap_or_else(|_| item.clone());
+        if seen.insert(identifier) {
+            unique.push(item);
+        } else {
+            merged += 1;
+        }
+    }
+    (unique, merged)
+}
+
+/// Download the items of each search in turn, each into its own directory unless a
+/// template is given. A failed item is reported and skipped. Returns the exit code.
+///
+/// The metadata of the next few items is fetched while the current item downloads,
+/// with at most `--metadata-jobs` metadata requests in flight.
+async fn download_items(searches: &[Search], ctx: &Context<'_>) -> i32 {
+    use futures::StreamExt;
+
+    let (identifiers, merged) = merge_duplicate_items(searches.iter().flat_map(|search| &search.identifiers));
+    if merged > 0 {
+        status!("Merged {} duplicate items", merged);
+    }
+    let jobs: usize = ctx.matches.value_of_t("metadata-jobs").unwrap_or_else(|e| e.exit());
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(jobs.max(1));
+
+    let prefetch = async {
+        // Owning the sender closes the channel once every item is prepared
+        let sender = sender;
+        let mut prepared = futures::stream::iter(&identifiers)
+            .map(|identifier| async move {
+                let mut log = Vec::new();
+                let prepared = ctx.within_deadline(prepare_item(identifier, Some("{identifier}"), ctx, &mut log)).await;
+                (identifier.as_str(), log, prepared)
+            })
+            .buffered(jobs.max(1));
+        while let Some(item) = prepared.next().await {
+            // The receiver is gone once the downloads stop early
+            if sender.send(item).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let parallel_items: usize = ctx.matches.value_of_t("parallel-items").unwrap_or_else(|e| e.exit());
+    if parallel_items > 1 {
+        let _ = PARALLEL_PROGRESS.set(indicatif::MultiProgress::new());
+    }
+    let downloads = async move {
+        let mut failed = Vec::new();
+        let mut failed_files = 0;
+        let mut downloaded = std::collections::HashSet::new();
+        let mut results = futures::stream::poll_fn(|cx| receiver.poll_recv(cx))
+            .map(|(identifier, log, prepared)| async move {
+                status!(" ");
+                let download = ctx.within_deadline(download_prepared(log, prepared, Some("{identifier}"), ctx));
+                // The lines of items downloading at the same time are told apart by their identifier
+                let result = match parallel_items {
+                    1 => download.await,
+                    _ => ITEM_PREFIX.scope(format!("[{}] ", identifier), download).await,
+                };
+                (identifier, result)
+            })
+            .buffer_unordered(parallel_items.max(1));
+        // Stopping drops the items still downloading, their partial files are resumed next time
+        while let Some((identifier, result)) = results.next().await {
+            match result {
+                Ok(outcome) => {
+                    downloaded.insert(identifier);
+                    failed_files += outcome.failed_files.len();
+                }
+                Err(e) => {
+                    failed.push(identifier);
+                    if let Some(
+                        IaGetError::RetryBudgetExhausted { .. }
+                        | IaGetError::InsufficientSpace { .. }
+                        | IaGetError::DiskFull { .. }
+                        | IaGetError::TimedOut { .. },
+                    ) = e.downcast_ref::<IaGetError>()
+                    {
+                        report_error("Stopping", &*e, Some(identifier));
+                        break;
+                    }
+                    report_error("Skipping item", &*e, Some(identifier));
+                }
+            }
+        }
+        (failed, failed_files, downloaded)
+    };
+
+    let ((), (failed, failed_files, downloaded)) = futures::join!(prefetch, downloads);
+
+    summary!(" ");
+    summary!(
+        "📚 Items        {} downloaded, {} failed, {} not attempted, {} retries",
+        downloaded.len(),
+        failed.len(),
+        identifiers.len() - downloaded.len() - failed.len(),
+        ctx.total_retries.load(Ordering::Relaxed)
+    );
+    if searches.len() > 1 {
+        for search in searches {
+            let done = search.identifiers.iter().filter(|identifier| downloaded.contains(identifier.as_str())).count();
+            summary!("├╼ Query        {} ({}/{} downloaded)", search.query, done, search.identifiers.len());
+        }
+    }
+    for identifier in &failed {
+        summary!("├╼ Failed       {}", identifier);
+    }
+    if !failed.is_empty() {
+        EXIT_PARTIAL_FAILURE
+    } else if failed_files > 0 {
+        EXIT_CHECKSUM_FAILURE
+    } else {
+        0
+    }
+}
+
+/// An item whose metadata has been fetched, ready for its files to be downloaded.
+struct PreparedItem {
+    identifier: String,
+    xml_url: String,
+    /// The item's download directory, the XML URL without the XML file name.
+    item_url: reqwest::Url,
+    files: XmlFiles,
+    /// Raw metadata API response, only fetched when a template or sidecar needs it.
+    raw_metadata: Option<serde_json::Value>,
+}
+
+/// Check an item is online and fetch its file list. Status lines are added to
+/// `log` rather than printed so several items can be prepared at once.
+async fn prepare_item(item: &str, default_template: Option<&str>, ctx: &Context<'_>, log: &mut Vec<String>) -> Result<PreparedItem, Box<dyn Error>> {
+    let matches = ctx.matches;
+    let client = &ctx.client;
+
+    let identifier = match normalize_archive_identifier(item) {
+        Ok(identifier) => identifier,
+        Err(e) => {
+            log.push(format!("Archive.org item: {}", item));
+            return Err(e.into());
+        }
+    };
+    let details_url = format!("https://archive.org/details/{}", identifier);
+
+    log.push(format!("Archive.org URL: {}", details_url));
+    match is_url_accessible(client, &details_url).await {
+        Ok(_) => log.push("╰╼ Archive.org URL online: 🟢".to_string()),
+        Err(e) => {
+            log.push("├╼ Archive.org URL online: 🔴".to_string());
+            return Err(explain_forbidden(client, &identifier, e.into()).await);
+        }
+    }
+
+    let xml_url = get_xml_url(&details_url);
+    log.push(format!("Archive.org XML: {}", xml_url));
+
+    match is_url_accessible(client, &xml_url).await {
+        Ok(_) => log.push("├╼ Archive.org XML online: 🟢".to_string()),
+        Err(e) => {
+            log.push("├╼ Archive.org XML online: 🔴".to_string());
+            return Err(explain_forbidden(client, &identifier, e.into()).await);
+        }
+    }
+
+    log.push("├╼ Parsing XML file        👀".to_string());
+    let mut item_url = reqwest::Url::parse(&xml_url)?;
+    item_url
+        .path_segments_mut()
+        .map_err(|_| "Archive.org XML URL cannot be a base URL")?
+        .pop();
+
+    // Download XML file
+    let verbose = matches.is_present("verbose");
+    let response = fetch_xml(client, &xml_url, !matches.is_present("no-compress"), verbose, log).await?;
+    let files: XmlFiles = from_str(&response)?;
+    log.push("╰╼ Done                    👍️".to_string());
+
+    let template = matches.value_of("output-template").or(default_template);
+    let needs_metadata = matches.is_present("write-metadata-sidecar") || matches.is_present("server");
+    let raw_metadata = if needs_metadata || template.is_some_and(template_needs_metadata) {
+        Some(fetch_item_metadata(client, &identifier).await?)
+    } else {
+        None
+    };
+    if let Some(restriction) = raw_metadata.as_ref().and_then(item_restriction) {
+        return Err(restriction.into());
+    }
+
+    Ok(PreparedItem { identifier, xml_url, item_url, files, raw_metadata })
+}
+
+/// Fetch the metadata for one archive.org item and download its files.
+///
+/// `item` is an identifier or archive.org URL. `default_template` names the
+/// output directory when `--output-template` is not given.
+async fn download_item(item: &str, default_template: Option<&str>, ctx: &Context<'_>) -> Result<ItemOutcome, Box<dyn Error>> {
+    let mut log = Vec::new();
+    let prepared = prepare_item(item, default_template, ctx, &mut log).await;
+    download_prepared(log, prepared, default_template, ctx).await
+}
+
+/// Print the status lines of a prepared item, then download its files.
+async fn download_prepared(
+    log: Vec<String>,
+    prepared: Result<PreparedItem, Box<dyn Error>>,
+    default_template: Option<&str>,
+    ctx: &Context<'_>,
+) -> Result<ItemOutcome, Box<dyn Error>> {
+    for line in &log {
+        status!("{}", line);
+    }
+    let PreparedItem { identifier, xml_url, item_url, files, raw_metadata } = prepared?;
+    let identifier = identifier.as_str();
+
+    let matches = ctx.matches;
+    let json = matches.is_present("json");
+    let read_only = matches.is_present("read-only");
+    let final_verify = matches.is_present("final-verify");
+    let preserve_mtime = matches.is_present("preserve-mtime");
+    let update_from = matches.value_of("update-from");
+    let link_existing = matches.is_present("link-existing");
+    let overwrite: OverwritePolicy = matches.value_of_t("overwrite").unwrap_or_else(|e| e.exit());
+    let max_rate_per_file = matches.value_of("max-rate-per-file").map(parse_size).transpose()?;
+    let dedupe = matches.is_present("dedupe");
+    let link_dupes = matches.is_present("link-dupes");
+    let chunks: u64 = matches.value_of_t("chunks").unwrap_or_else(|e| e.exit());
+    let retries: u32 = matches.value_of_t("retries").unwrap_or_else(|e| e.exit());
+    let retry_on_mismatch: u32 = matches.value_of_t("retry-on-mismatch").unwrap_or_else(|e| e.exit());
+    let progress_interval = Duration::from_millis(matches.value_of_t("progress-interval").unwrap_or_else(|e| e.exit()));
+    let template = matches.value_of("output-template").or(default_template);
+    let write_sidecar = matches.is_present("write-metadata-sidecar");
+    let strict_checksum = matches.is_present("strict-checksum");
+    let verify_min_size = matches.value_of("verify-min-size").map(parse_size).transpose()?.unwrap_or(0);
+    // Existing local copies are hashed in full with --verify-on-resume, whatever their size
+    let existing_min_size = if matches.is_present("verify-on-resume") { 0 } else { verify_min_size };
+    let decompress_formats: Vec<ArchiveFormat> = if matches.is_present("decompress") {
+        matches.values_of_t("decompress-formats").unwrap_or_else(|e| e.exit())
+    } else {
+        Vec::new()
+    };
+    let remove_compressed = matches.is_present("remove-compressed");
+    let force_server = matches.is_present("force-server");
+
+    let output_dir = match template {
+        Some(template) => {
+            let item: ItemMetadata = match &raw_metadata {
+                Some(raw) => serde_json::from_value(raw.clone())?,
+                None => ItemMetadata::default(),
+            };
+            let fallback = matches.value_of("template-fallback").unwrap_or_default();
+            let output_dir = render_output_template(template, identifier, &item.metadata, fallback)?;
+            status!("Output directory: {}", output_dir.display());
+            output_dir
+        }
+        None => PathBuf::from("."),
+    };
+
+    if matches.is_present("diff") {
+        let previous_path = match matches.value_of("against") {
+            Some(path) => PathBuf::from(path),
+            None => output_dir.join(xml_url.rsplit('/').next().unwrap_or_default()),
+        };
+        let previous = fs::read_to_string(&previous_path)
+            .map_err(|e| format!("Failed to read previous metadata {}: {}", previous_path.display(), e))?;
+        let previous: XmlFiles = from_str(&previous)?;
+        let diff = diff_metadata(&previous.files, &files.files);
+        if json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+        } else {
+            print_metadata_diff(&diff, &previous.files, &files.files);
+        }
+        return Ok(ItemOutcome::default());
+    }
+
+    if matches.is_present("health") {
+        let report = health_report(&files.files);
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_health_report(&report);
+        }
+        return Ok(ItemOutcome::default());
+    }
+
+    if let Some(raw) = raw_metadata.as_ref().filter(|_| write_sidecar) {
+        let metadata_path = write_metadata_sidecar(&output_dir, identifier, raw, &files.files)?;
+        status!("Metadata sidecar: {}", metadata_path.display());
+    }
+
+    let mirror_url = match matches.value_of("server") {
+        Some(server) => {
+            let url = mirror_item_url(server, raw_metadata.as_ref(), force_server)?;
+            status!("Mirror: {}", url);
+            Some(url)
+        }
+        None => None,
+    };
+
+    let mut files = files.files;
+    if matches.is_present("preflight-sizes") {
+        let unknown = files.iter().filter(|file| file.size.is_none()).count();
+        if unknown > 0 {
+            let found = preflight_sizes(ctx, mirror_url.as_ref().unwrap_or(&item_url), &mut files).await;
+            status!("Preflight sizes: {} of {} unknown sizes found", found, unknown);
+        }
+    }
+
+    let external_checksums = match matches.value_of("checksum-source") {
+        Some(source) => {
+            let checksums = external_checksums(ctx, source, mirror_url.as_ref().unwrap_or(&item_url), &files).await?;
+            status!("Checksum source: {} ({} files)", source, checksums.len());
+            checksums
+        }
+        None => std::collections::HashMap::new(),
+    };
+
+    let filters = &ctx.filters;
+    if let Some(path) = matches.value_of("plan-out") {
+        let plan = DownloadPlan::new(identifier, &files, filters);
+        fs::write(path, serde_json::to_string_pretty(&plan)?)?;
+        let selected = plan.files.iter().filter(|entry| entry.selected).count();
+        status!("Download plan: {} ({} of {} files selected)", path, selected, plan.files.len());
+        return Ok(ItemOutcome::default());
+    }
+
+    if let Some(names) = matches.values_of("explain") {
+        let range = matches.value_of("range").map(parse_range).transpose()?;
+        let max_files = matches.is_present("max-files").then(|| matches.value_of_t("max-files").unwrap_or_else(|e| e.exit()));
+        let explanations: Vec<SelectionExplanation> =
+            names.map(|name| explain_selection(&files, filters, range, max_files, name)).collect();
+        if json {
+            println!("{}", serde_json::to_string_pretty(&explanations)?);
+        } else {
+            for explanation in &explanations {
+                let mark = if explanation.selected { "✅" } else { "🚫" };
+                summary!("{} {}: {}", mark, explanation.name, explanation.reason);
+            }
+        }
+        return Ok(ItemOutcome::default());
+    }
+
+    let total_files = files.len();
+    let item_paths: Vec<PathBuf> = files.iter().filter_map(|file| confined_path(&output_dir, &file.name).ok()).collect();
+    let orphans = orphaned_parts(&output_dir, &files);
+    if !orphans.is_empty() {
+        status!("⚠️ Orphaned partial downloads {}, not part of this item", orphans.len());
+        for path in &orphans {
+            status!("├╼ {}", path.display());
+        }
+    }
+    let plan = ctx.plan_for(identifier);
+    let mut files = match plan {
+        Some(plan) => {
+            let files = plan.select(files);
+            status!("Planned files: {} of {} selected", files.len(), total_files);
+            files
+        }
+        None => filter_files(files, filters),
+    };
+    if plan.is_none() && !filters.is_empty() {
+        status!("Filtered files: {} of {} selected", files.len(), total_files);
+    }
+    if let Some(range) = matches.value_of("range") {
+        // Sorted by name so that every machine sharding the item sees the same order
+        let (start, end) = parse_range(range)?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let end = end.unwrap_or(files.len()).min(files.len());
+        let start = start.min(end);
+        status!("Range files: {} to {} of {} by name", start, end, files.len());
+        files = files.drain(start..end).collect();
+    }
+    if matches.is_present("max-files") {
+        let max_files: usize = matches.value_of_t("max-files").unwrap_or_else(|e| e.exit());
+        if files.len() > max_files {
+            status!("Limited files: first {} kept, {} omitted", max_files, files.len() - max_files);
+            files.truncate(max_files);
+        }
+    }
+
+    // Names come from the item metadata, which is not trusted to stay inside the output directory
+    for file in &files {
+        confined_path(&output_dir, &file.name)?;
+    }
+    let clashes = name_clashes(&files);
+    if !clashes.is_empty() {
+        status!("⚠️ Name clashes {}, skipped rather than written over", clashes.len());
+        for (index, earlier) in &clashes {
+            status!("├╼ {} clashes with {}", files[*index].name, files[*earlier].name);
+        }
+        files = files
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, file)| (!clashes.iter().any(|(clash, _)| *clash == index)).then_some(file))
+            .collect();
+    }
+
+    if matches.is_present("format-report") {
+        let report = format_report(&files);
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_format_report(&report);
+        }
+        return Ok(ItemOutcome::default());
+    }
+
+    if files.is_empty() {
+        if total_files > 0 && plan.is_none() {
+            status!("├╼ Active filters: {}", filters.describe().join(" "));
+        }
+        return Err(IaGetError::NoFiles { filtered_out: total_files }.into());
+    }
+
+    if matches.is_present("dry-run") {
+        let report = dry_run_report(&files, &output_dir, overwrite, existing_min_size).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_dry_run_report(&report, matches.is_present("verbose"));
+        }
+        return Ok(ItemOutcome::default());
+    }
+
+    // The server-side zip holds every file, so it only stands in for an unfiltered download
+    if matches.is_present("as-zip") {
+        if plan.is_some() || !filters.is_empty() || files.len() < total_files {
+            status!("Item zip: skipped, not every file is selected");
+        } else {
+            status!(" ");
+            status!("🗜️ Item zip     {}.zip", identifier);
+            match download_item_zip(ctx, &item_url, identifier, &output_dir).await {
+                Ok(zip_path) => {
+                    if decompress_formats.contains(&ArchiveFormat::Zip) {
+                        match extract_in_background(&zip_path, ArchiveFormat::Zip, &item_paths).await {
+                            Ok((destination, extracted)) => {
+                                status!("├╼ Extracted    📂 {} files to {}", extracted, destination.display());
+                                let name = format!("{}.zip", identifier);
+                                finish_extraction(ctx, identifier, &name, &zip_path, &destination, remove_compressed);
+                            }
+                            Err(e) => status!("├╼ Extract failed: {}", e),
+                        }
+                    }
+                    status!("╰╼ Unverified:  ⚠️ archive.org publishes no checksum for item zips");
+                    return Ok(ItemOutcome::default());
+                }
+                Err(e) => status!("╰╼ Unavailable, downloading files one by one: {}", e),
+            }
+        }
+    }
+
+    let min_free_space = matches.value_of("min-free-space").map(parse_size).transpose()?;
+    if let Some(available) = available_space(&output_dir) {
+        let required: u64 = files.iter().map(|file| remaining_bytes(file, &output_dir)).sum();
+        if let Some(min_free) = min_free_space.filter(|min_free| available < *min_free) {
+            return Err(IaGetError::InsufficientSpace { available, required: min_free }.into());
+        }
+        if required + min_free_space.unwrap_or(0) > available {
+            let reserve = min_free_space.map(|min_free| format!(" plus {} kept free", format_size(min_free))).unwrap_or_default();
+            status!(
+                "⚠️ Free space   {} available, the download needs {}{}",
+                format_size(available),
+                format_size(required),
+                reserve
+            );
+        }
+    }
+
+    ctx.event(
+        "download_started",
+        serde_json::json!({
+            "identifier": identifier,
+            "files": files.len(),
+            "bytes": files.iter().filter_map(|file| file.size).sum::<u64>(),
+        }),
+    );
+
+    // Iterate over the XML files struct and print every field
+    let mut stats = DownloadStats::new(&files);
+    // Local copy of the first file seen with each checksum, used by --dedupe
+    let mut first_copies: std::collections::HashMap<String, PathBuf> = std::collections::HashMap::new();
+    let mut duplicates = 0;
+    let mut duplicate_bytes = 0;
+    let mut outcome = ItemOutcome::default();
+    // Files with a checksum, hashed again by --final-verify once every download is done
+    let mut final_checks: Vec<(String, PathBuf, HashAlgorithm, String)> = Vec::new();
+    // Files left out of the output directory by --dedupe, --update-from or --remove-compressed
+    let mut not_local = std::collections::HashSet::new();
+
+    for file in files {
+        if final_verify {
+            let expected = match (&file.md5, external_checksums.get(&file.name)) {
+                (Some(md5), _) => Some((HashAlgorithm::Md5, md5.clone())),
+                (None, Some(external)) => Some((external.algorithm, external.hex.clone())),
+                (None, None) => None,
+            };
+            if let Some((algorithm, hex)) = expected {
+                final_checks.push((file.name.clone(), confined_path(&output_dir, &file.name)?, algorithm, hex));
+            }
+        }
+        let mut absolute_url = construct_download_url(mirror_url.as_ref().unwrap_or(&item_url), &file.name)?;
+        // A pinned mirror that keeps failing gives way to the usual download URL
+        let mut fallback_url = match mirror_url.as_ref().filter(|_| !force_server) {
+            Some(_) => Some(construct_download_url(&item_url, &file.name)?),
+            None => None,
+        };
+        status!(" ");
+        status!("📦️ Filename     {}", file.name);
+        let local_path = confined_path(&output_dir, &file.name)?;
+        status!("├╼ Overall      {}", stats.summary());
+        let mut download_action = "╰╼ Downloading  ";
+        let mut download_complete = "├╼ Downloading  ";
+        let checksum = file.md5.clone().or_else(|| file.sha1.clone());
+
+        // Content identical to a file already handled is not downloaded twice
+        if let (true, Some(first)) = (dedupe, checksum.as_ref().and_then(|checksum| first_copies.get(checksum))) {
+            if link_dupes && !local_path.exists() {
+                ensure_parent_dir(&local_path)?;
+                link_or_copy(first, &local_path)?;
+            } else if !link_dupes {
+                not_local.insert(file.name.clone());
+            }
+            status!("╰╼ Duplicate:   ♊ {}", first.display());
+            duplicates += 1;
+            duplicate_bytes += file.size.unwrap_or(0);
+            stats.complete_file(&file, 0);
+            continue;
+        }
+
+        // Files already present in the reference directory are not downloaded again
+        if let Some(reference_dir) = update_from {
+            let reference_path = Path::new(reference_dir).join(&file.name);
+            if !local_path.exists() && reference_matches(&reference_path, &file) {
+                if link_existing {
+                    ensure_parent_dir(&local_path)?;
+                    link_or_copy(&reference_path, &local_path)?;
+                    status!("╰╼ Linked:      🔗");
+                    if let Some(checksum) = checksum {
+                        first_copies.entry(checksum).or_insert(local_path);
+                    }
+                } else {
+                    status!("╰╼ Referenced:  ⏭️");
+                    not_local.insert(file.name.clone());
+                }
+                stats.complete_file(&file, 0);
+                continue;
+            }
+        }
+
+        // Stop before a file that would take free space below the minimum,
+        // partial downloads are kept and resumed by the next run
+        if let Some(min_free) = min_free_space {
+            if let Some(available) = available_space(&output_dir) {
+                let required = remaining_bytes(&file, &output_dir) + min_free;
+                if available < required {
+                    ctx.record_failure(identifier, &file.name, "insufficient_space", format!("{} available", format_size(available)));
+                    return Err(IaGetError::InsufficientSpace { available, required }.into());
+                }
+            }
+        }
+
+        if let Err(e) = ctx.check_deadline() {
+            summary!("⏱️ Stopped      {}", stats.summary());
+            return Err(e.into());
+        }
+
+        // Check if the file already exists
+        let part_path = part_path(&local_path);
+        let replace = plan.is_some_and(|plan| plan.files.iter().any(|entry| entry.name == file.name && entry.replace));
+        let overwrite = if replace { OverwritePolicy::Always } else { overwrite };
+        if local_path.exists() {
+            if overwrite == OverwritePolicy::IfDifferent {
+                status!("├╼ Hash Check   🧮");
+            }
+            match existing_file_action(&file, &local_path, overwrite, existing_min_size).await? {
+                ExistingFileAction::Keep => {
+                    if overwrite == OverwritePolicy::Never {
+                        status!("╰╼ Skipped:     ⏭️");
+                    } else {
+                        status!("╰╼ Completed:   ✅");
+                        if read_only {
+                            set_read_only(&local_path, true)?;
+                        }
+                    }
+                    if let Some(checksum) = checksum {
+                        first_copies.entry(checksum).or_insert(local_path);
+                    }
+                    stats.complete_file(&file, 0);
+                    continue;
+                }
+                ExistingFileAction::Resume => {
+                    // The download appends to the file, which must not reach a linked copy
+                    break_hardlink(&local_path)?;
+                    // A previous run may have marked the file read-only
+                    set_read_only(&local_path, false)?;
+                    // Carry on from the short file as if it were a partial download
+                    fs::rename(&local_path, &part_path).map_err(|e| file_system_error(e, &part_path))?;
+                }
+                ExistingFileAction::Replace => {
+                    download_action = "╰╼ Replacing    ";
+                    download_complete = "├╼ Replacing    ";
+                    set_read_only(&local_path, false)?;
+                    fs::remove_file(&local_path)?;
+                }
+            }
+        }
+
+        // A checksum mismatch is retried up to --retry-on-mismatch times
+        let mut mismatches = 0;
+        let (file_size, total_bytes, size_checked, external, verified) = loop {
+            // A partial download left by an earlier run is resumed, verified when
+            // it already has the expected size, or discarded when it is too big
+            let mut part_complete = false;
+            if part_path.exists() {
+                let part_size = fs::metadata(&part_path)?.len();
+                match file.size {
+                    _ if overwrite == OverwritePolicy::Always => fs::remove_file(&part_path)?,
+                    Some(size) if part_size > size => {
+                        status!("├╼ Discarding oversized partial download");
+                        fs::remove_file(&part_path)?;
+                    }
+                    Some(size) if part_size == size => part_complete = true,
+                    _ => {
+                        download_action = "╰╼ Resuming     ";
+                        download_complete = "├╼ Resuming     ";
+                    }
+                }
+            }
+
+            let (file_size, total_bytes) = if part_complete {
+                status!("├╼ Partial download already complete");
+                (0, 0)
+            } else {
+                ensure_parent_dir(&part_path).map_err(|e| file_system_error(e, &part_path))?;
+
+                // A partial download is resumed from its end
+                let file_size = fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+                let throttle = Throttle {
+                    global: ctx.max_rate.as_ref(),
+                    file: max_rate_per_file.map(RateLimiter::new),
+                };
+
+                // Large new files can be fetched as several byte ranges at once
+                let chunked_size = match file.size {
+                    Some(size) if chunks > 1 && file_size == 0 && size >= CHUNKED_MIN_SIZE => {
+                        supports_ranges(&ctx.download_client, &absolute_url, ctx.client_config.read_timeout).await.then_some(size)
+                    }
+                    _ => None,
+                };
+
+                let pb = progress_bar(chunked_size.or(file.size).unwrap_or(0), download_action);
+                // Only shown by --simple-progress
+                pb.set_message(format!(
+                    "{:.1}% overall, file {}/{} {}: ",
+                    stats.percent(),
+                    stats.completed_files + 1,
+                    stats.total_files,
+                    file.name
+                ));
+                let mut total_bytes: u64 = file_size;
+                let mut attempt = 0;
+                loop {
+                    let remaining = match chunked_size {
+                        Some(size) => Some(size),
+                        None => file.size.map(|size| size.saturating_sub(total_bytes)),
+                    };
+                    let transfer = async {
+                        match chunked_size {
+                            Some(size) => download_chunked(ctx, &absolute_url, &part_path, size, chunks, &pb, &throttle)
+                                .await
+                                .map(|()| total_bytes = size),
+                            None => download_stream(ctx, &absolute_url, &part_path, &mut total_bytes, &pb, &throttle, progress_interval).await,
+                        }
+                    };
+                    let result = match remaining.and_then(|bytes| ctx.client_config.transfer_timeout(bytes)) {
+                        Some(limit) => tokio::time::timeout(limit, transfer)
+                            .await
+                            .unwrap_or_else(|_| Err(IaGetError::TransferTimeout { seconds: limit.as_secs() }.into())),
+                        None => transfer.await,
+                    };
+                    // Writes to the partial file that fail get advice on the cause
+                    let result = result.map_err(|e| match e.downcast::<std::io::Error>() {
+                        Ok(e) => file_system_error(*e, &part_path),
+                        Err(e) => e,
+                    });
+                    match result {
+                        Ok(()) => break,
+                        Err(e) if attempt < retries && is_transient(&*e) => {
+                            if let Err(e) = ctx.take_retry() {
+                                ctx.record_failure(identifier, &file.name, error_kind(&e), e.to_string());
+                                return Err(e.into());
+                            }
+                            attempt += 1;
+                            stats.retries += 1;
+                            suspend_progress(&pb, || status!("├╼ Retrying     {}/{} after {}", attempt, retries, e));
+                            tokio::time::sleep(retry_delay(&*e, attempt)).await;
+                            if chunked_size.is_some() {
+                                pb.set_position(0);
+                            }
+                        }
+                        Err(e) => match fallback_url.take() {
+                            Some(url) if is_transient(&*e) => {
+                                suspend_progress(&pb, || status!("├╼ Falling back to the default mirror after {}", e));
+                                absolute_url = url;
+                                attempt = 0;
+                                if chunked_size.is_some() {
+                                    pb.set_position(0);
+                                }
+                            }
+                            _ => {
+                                if let Some(IaGetError::TimedOut { .. }) = e.downcast_ref::<IaGetError>() {
+                                    pb.abandon();
+                                    summary!("⏱️ Stopped      {}", stats.summary());
+                                }
+                                let e = explain_forbidden(&ctx.client, identifier, e).await;
+                                ctx.record_failure(identifier, &file.name, error_kind(&*e), e.to_string());
+                                return Err(e);
+                            }
+                        },
+                    }
+                }
+
+                if !SIMPLE_PROGRESS.load(Ordering::Relaxed) {
+                    pb.set_style(
+                        ProgressStyle::default_bar()
+                            .template(format!("{{prefix}}{}{{elapsed_precise}}     {{bar:40.green/green}} {{{}}}", download_complete, size_key("total_bytes")).as_str()).expect("REASON")
+                    );
+                }
+                pb.finish();
+                (file_size, total_bytes)
+            };
+
+            // Files without an MD5 in the metadata may have one from --checksum-source
+            let external = file.md5.is_none().then(|| external_checksums.get(&file.name)).flatten();
+            // Small files only need to have the expected size
+            let size_checked = file.md5.is_some() && file.size.is_some_and(|size| size < verify_min_size);
+            let verification: Result<(), (&str, String)> = match (&file.md5, file.size) {
+                (_, Some(size)) if size_checked => {
+                    let local_size = fs::metadata(&part_path)?.len();
+                    if local_size == size {
+                        Ok(())
+                    } else {
+                        Err(("size_mismatch", format!("expected {} bytes, got {}", size, local_size)))
+                    }
+                }
+                (Some(expected_md5), _) => {
+                    status!("├╼ Hash Check   🧮");
+                    // Calculate the MD5 hash of the local file
+                    let local_md5 = calculate_md5(&part_path).await.map_err(|e| format!("Failed to calculate MD5 hash: {}", e))?;
+                    if &local_md5 == expected_md5 {
+                        Ok(())
+                    } else {
+                        ctx.log_hash_error(identifier, &file, expected_md5, &local_md5, absolute_url.as_str());
+                        Err(("checksum_mismatch", format!("expected MD5 {}, got {}", expected_md5, local_md5)))
+                    }
+                }
+                (None, _) => match external {
+                    Some(expected) => {
+                        status!("├╼ Hash Check   🧮");
+                        let actual = hash_in_background(&part_path, expected.algorithm)
+                            .await
+                            .map_err(|e| format!("Failed to calculate {:?} hash: {}", expected.algorithm, e))?;
+                        if actual == expected.hex {
+                            Ok(())
+                        } else {
+                            ctx.log_hash_error(identifier, &file, &expected.hex, &actual, absolute_url.as_str());
+                            Err(("checksum_mismatch", format!("expected {:?} {}, got {}", expected.algorithm, expected.hex, actual)))
+                        }
+                    }
+                    // Without a checksum the file can only be trusted when not in strict mode
+                    None if strict_checksum => Err(("missing_checksum", "no MD5 in the metadata".to_string())),
+                    None => Ok(()),
+                },
+            };
+            match verification {
+                // A corrupted transfer is usually fixed by downloading the file again
+                Err((kind, message)) if kind != "missing_checksum" && mismatches < retry_on_mismatch => {
+                    mismatches += 1;
+                    status!("├╼ Mismatch     retrying {}/{} after {}", mismatches, retry_on_mismatch, message);
+                    fs::remove_file(&part_path)?;
+                    if let Some(url) = fallback_url.take() {
+                        absolute_url = url;
+                    }
+                    continue;
+                }
+                Err((kind, message)) => {
+                    ctx.record_failure(identifier, &file.name, kind, message);
+                    break (file_size, total_bytes, size_checked, external, false);
+                }
+                Ok(()) => break (file_size, total_bytes, size_checked, external, true),
+            }
+        };
+        if size_checked {
+            outcome.size_checked_files.push(file.name.clone());
+        }
+        if external.is_none() && file.md5.is_none() {
+            outcome.unverified_files.push(file.name.clone());
+        }
+        if !verified {
+            status!("╰╼ Failure:     ❌");
+            outcome.failed_files.push(file.name.clone());
+            // Content that does not match its checksum is not worth resuming
+            if file.md5.is_some() || external.is_some() {
+                fs::remove_file(&part_path)?;
+            }
+        } else {
+            if let Some(mtime) = file.mtime.as_deref().filter(|_| preserve_mtime) {
+                match file_mtime(mtime, SystemTime::now()) {
+                    Ok((time, warning)) => {
+                        if let Some(warning) = warning {
+                            status!("├╼ ⚠️ {}", warning);
+                        }
+                        fs::File::options().write(true).open(&part_path)?.set_modified(time)?;
+                    }
+                    Err(e) => status!("├╼ ⚠️ {}, keeping the download time", e),
+                }
+            }
+            // Only verified downloads take their final name, so an interrupted
+            // run never leaves an unchecked file behind under that name
+            fs::rename(&part_path, &local_path).map_err(|e| file_system_error(e, &local_path))?;
+            let mut removed = false;
+            match detect_archive(&local_path) {
+                Ok(Some(format)) if decompress_formats.contains(&format) => match extract_in_background(&local_path, format, &item_paths).await {
+                    Ok((destination, extracted)) => {
+                        status!("├╼ Extracted    📂 {} files to {}", extracted, destination.display());
+                        removed = finish_extraction(ctx, identifier, &file.name, &local_path, &destination, remove_compressed);
+                    }
+                    Err(e) => status!("├╼ Extract failed: {}", e),
+                },
+                Ok(_) => {}
+                Err(e) => status!("├╼ Extract failed: {}", e),
+            }
+            if size_checked {
+                status!("╰╼ Size OK:     📏");
+            } else if file.md5.is_some() || external.is_some() {
+                status!("╰╼ Success:     ✅");
+            } else {
+                status!("╰╼ Unverified:  ⚠️");
+            }
+            ctx.event(
+                "file_completed",
+                serde_json::json!({ "identifier": identifier, "name": file.name, "bytes": total_bytes - file_size }),
+            );
+            if removed {
+                not_local.insert(file.name.clone());
+            } else {
+                if read_only {
+                    set_read_only(&local_path, true)?;
+                }
+                if let Some(checksum) = checksum {
+                    first_copies.entry(checksum).or_insert(local_path);
+                }
+            }
+        }
+        stats.complete_file(&file, total_bytes - file_size);
+    }
+
+    let integrity = if final_verify {
+        final_checks.retain(|(name, ..)| !outcome.failed_files.contains(name) && !not_local.contains(name));
+        status!(" ");
+        status!("🔏 Final verify {} files", final_checks.len());
+        let checked = final_checks.len();
+        let failures = recheck_files(final_checks).await;
+        for (name, kind, message) in &failures {
+            ctx.record_failure(identifier, name, kind, message.clone());
+            outcome.failed_files.push(name.clone());
+        }
+        Some((checked, failures))
+    } else {
+        None
+    };
+
+    ctx.event(
+        "download_finished",
+        serde_json::json!({
+            "identifier": identifier,
+            "failed": outcome.failed_files.len(),
+            "unverified": outcome.unverified_files.len(),
+            "seconds": stats.started.elapsed().as_secs_f64(),
+        }),
+    );
+    summary!(" ");
+    if !status_shown() {
+        summary!("📦️ Item         {}", identifier);
+    }
+    summary!("📊 Complete     {} in {}", stats.summary(), HumanDuration(stats.started.elapsed()));
+    if dedupe {
+        summary!("♊ Duplicates   {} skipped, {} saved", duplicates, format_size(duplicate_bytes));
+    }
+    if !outcome.unverified_files.is_empty() {
+        summary!("⚠️ Unverified   {} files have no MD5 in the metadata", outcome.unverified_files.len());
+        for name in &outcome.unverified_files {
+            summary!("├╼ {}", name);
+        }
+    }
+    if !outcome.size_checked_files.is_empty() {
+        summary!("📏 Size checked {} files smaller than --verify-min-size", outcome.size_checked_files.len());
+    }
+    if !outcome.failed_files.is_empty() {
+        summary!("❌ Failed       {} files failed verification", outcome.failed_files.len());
+    }
+    match integrity {
+        Some((checked, failures)) if failures.is_empty() => summary!("🔏 Item integrity: OK ({} files)", checked),
+        Some((checked, failures)) => {
+            summary!("🔏 Item integrity: FAILED ({} of {} files)", failures.len(), checked);
+            for (name, _, message) in &failures {
+                summary!("├╼ {}: {}", name, message);
+            }
+        }
+        None => {}
+    }
+
+    Ok(outcome)
+}
+
+/// The command line arguments.
+fn app() -> App<'static> {
+    let name = env!("CARGO_PKG_NAME");
+    let version = env!("CARGO_PKG_VERSION");
+    let authors = env!("CARGO_PKG_AUTHORS");
+    let description = env!("CARGO_PKG_DESCRIPTION");
+
+    App::new(name)
+        .version(version)
+        .author(authors)
+        .about(description)
+        .arg(Arg::with_name("URL")
+             .help("Identifier of an archive.org item, or the URL of its details or download page. Several items, or - to read a list from stdin, are each downloaded to their own directory")
+             .required_unless_present_any(["search", "plan", "retry-file"])
+             .multiple_values(true)
+             .index(1))
+        .arg(Arg::with_name("parallel-items")
+             .help("Download this many items at the same time when downloading several, each line of their output starts with the identifier (at most 16 connections with --chunks)")
+             .long("parallel-items")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_concurrency)
+             .default_value("1"))
+        .arg(Arg::with_name("collection")
+             .help("List the identifiers of every item in a collection")
+             .long("collection")
+             .value_name("COLLECTION")
+             .takes_value(true)
+             .conflicts_with("URL"))
+        .arg(Arg::with_name("list-collections")
+             .help("List the collections the item belongs to, then exit")
+             .long("list-collections")
+             .conflicts_with_all(&["search", "plan", "retry-file"]))
+        .arg(Arg::with_name("queries")
+             .help("List the identifiers of the items matching each search query in a file, one query per line")
+             .long("queries")
+             .value_name("FILE")
+             .takes_value(true)
+             .conflicts_with("URL"))
+        .group(ArgGroup::with_name("search")
+             .args(&["collection", "queries"]))
+        .arg(Arg::with_name("download")
+             .help("Download every listed item of the --collection or --queries, each into its own directory")
+             .long("download")
+             .requires("search"))
+        .arg(Arg::with_name("limit")
+             .help("List or download at most this many items of the --collection, or of each query")
+             .long("limit")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_count)
+             .requires("search"))
+        .arg(Arg::with_name("max-items")
+             .help("List or download at most this many items across all --queries")
+             .long("max-items")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_count)
+             .requires("queries"))
+        .arg(Arg::with_name("metadata-jobs")
+             .help("Fetch the metadata of up to this many items at once while downloading a batch (at most 16)")
+             .long("metadata-jobs")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_concurrency)
+             .default_value("4"))
+        .arg(Arg::with_name("verbose")
+             .help("Print additional diagnostic information")
+             .short('v')
+             .long("verbose"))
+        .arg(Arg::with_name("no-compress")
+             .help("Do not request gzip compressed XML metadata (file downloads are never compressed)")
+             .long("no-compress"))
+        .arg(Arg::with_name("connect-timeout")
+             .help("Seconds to wait for a connection to be established (0 disables)")
+             .long("connect-timeout")
+             .value_name("SECS")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("30"))
+        .arg(Arg::with_name("read-timeout")
+             .help("Seconds to wait for a response or for more data before a download is considered stalled (0 disables)")
+             .long("read-timeout")
+             .value_name("SECS")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("60"))
+        .arg(Arg::with_name("timeout")
+             .help("Overall timeout in seconds for metadata requests; file downloads are not bounded (0 disables)")
+             .long("timeout")
+             .value_name("SECS")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("60"))
+        .arg(Arg::with_name("min-expected-rate")
+             .help("Give up on a file transfer that is slower than this rate on average (e.g. 50K), allowing --timeout on top")
+             .long("min-expected-rate")
+             .value_name("RATE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("user-agent")
+             .help("User-Agent header to send instead of the default, also read from IA_GET_USER_AGENT")
+             .long("user-agent")
+             .value_name("STRING")
+             .takes_value(true))
+        .arg(Arg::with_name("include-ext")
+             .help("Only download files with these extensions (comma separated)")
+             .long("include-ext")
+             .alias("include")
+             .value_name("EXT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true))
+        .arg(Arg::with_name("exclude-ext")
+             .help("Skip files with these extensions (comma separated)")
+             .long("exclude-ext")
+             .value_name("EXT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true))
+        .arg(Arg::with_name("include-from")
+             .help("Only download files matching the extensions or glob patterns in a file, one per line")
+             .long("include-from")
+             .value_name("FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("exclude-from")
+             .help("Skip files matching the extensions or glob patterns in a file, one per line")
+             .long("exclude-from")
+             .value_name("FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("include-formats")
+             .help("Only download files in these archive.org formats, e.g. \"Text PDF\" (comma separated)")
+             .long("include-formats")
+             .value_name("FORMAT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true))
+        .arg(Arg::with_name("plan-out")
+             .help("Write the item's files to a JSON plan, marking those selected by the filters, then exit")
+             .long("plan-out")
+             .value_name("FILE")
+             .takes_value(true)
+             .conflicts_with_all(&["search", "plan"]))
+        .arg(Arg::with_name("explain")
+             .help("Report whether a file is selected and which filter excluded it, then exit")
+             .long("explain")
+             .value_name("FILE")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .conflicts_with_all(&["search", "plan", "plan-out"]))
+        .arg(Arg::with_name("plan")
+             .help("Download the files marked as selected in a plan written by --plan-out")
+             .long("plan")
+             .value_name("FILE")
+             .takes_value(true)
+             .conflicts_with_all(&["URL", "search"]))
+        .arg(Arg::with_name("failures-out")
+             .help("Write the files that failed to download or verify to a JSON file, for use with --retry-file")
+             .long("failures-out")
+             .value_name("FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("event-log")
+             .help("Append a JSON line for each download started, file completed or failed and download finished to a file")
+             .long("event-log")
+             .value_name("FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("truncate-event-log")
+             .help("Empty the --event-log file before writing to it, instead of appending")
+             .long("truncate-event-log")
+             .requires("event-log"))
+        .arg(Arg::with_name("log-hash-errors")
+             .help("Append a JSON line with the expected and actual checksum, size and URL of each file that fails its hash check to a file")
+             .long("log-hash-errors")
+             .value_name("FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("retry-file")
+             .help("Download only the files listed in a file written by --failures-out")
+             .long("retry-file")
+             .value_name("FILE")
+             .takes_value(true)
+             .conflicts_with_all(&["URL", "search", "plan", "plan-out"]))
+        .arg(Arg::with_name("min-size")
+             .help("Skip files smaller than this size (e.g. 500K, 2M)")
+             .long("min-size")
+             .value_name("SIZE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("max-size")
+             .help("Skip files larger than this size (e.g. 500K, 2M)")
+             .long("max-size")
+             .value_name("SIZE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("preflight-sizes")
+             .help("Look up sizes missing from the metadata with HEAD requests before filtering")
+             .long("preflight-sizes"))
+        .arg(Arg::with_name("prefer-format")
+             .help("When the same content is available in several of these extensions or formats, only download the first listed (comma separated)")
+             .long("prefer-format")
+             .value_name("FORMAT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true))
+        .arg(Arg::with_name("max-files")
+             .help("Download at most this many of the selected files, in listing order")
+             .long("max-files")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_count))
+        .arg(Arg::with_name("range")
+             .help("Download only files START to END of the selection sorted by name, counting from 0 and excluding END, to share an item between machines")
+             .long("range")
+             .value_name("START:END")
+             .takes_value(true)
+             .validator(parse_range))
+        .arg(Arg::with_name("with-sidecars")
+             .help("Also download subtitles, cover art and other sidecars named like each selected file")
+             .long("with-sidecars"))
+        .arg(Arg::with_name("sidecar-ext")
+             .help("Extensions treated as sidecars by --with-sidecars (comma separated)")
+             .long("sidecar-ext")
+             .value_name("EXT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true)
+             .default_value("srt,vtt,ass,ssa,sub,idx,lrc,cue,nfo,jpg,png"))
+        .arg(Arg::with_name("allow-redirect-host")
+             .help("Also follow redirects to this host and its subdomains (archive.org is always allowed)")
+             .long("allow-redirect-host")
+             .value_name("HOST")
+             .takes_value(true)
+             .multiple_occurrences(true))
+        .arg(Arg::with_name("units")
+             .help("Show sizes in 1024 based IEC units (MiB) or 1000 based SI units (MB), which also sets what K, M and G mean in sizes given to other options")
+             .long("units")
+             .value_name("UNITS")
+             .takes_value(true)
+             .possible_values(["iec", "si"])
+             .default_value("iec"))
+        .arg(Arg::with_name("no-color")
+             .help("Do not color the output, also turned off by the NO_COLOR environment variable or when not writing to a terminal")
+             .long("no-color"))
+        .arg(Arg::with_name("server")
+             .help("Download files from this mirror, which must be one of the item's workable servers")
+             .long("server")
+             .value_name("HOST")
+             .takes_value(true))
+        .arg(Arg::with_name("force-server")
+             .help("Use the --server mirror even if it is not a workable server, and never fall back")
+             .long("force-server")
+             .requires("server"))
+        .arg(Arg::with_name("max-rate")
+             .help("Cap the combined download rate, in bytes per second (e.g. 500K, 2M)")
+             .long("max-rate")
+             .value_name("RATE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("max-rate-per-file")
+             .help("Cap the download rate of each file, in bytes per second (e.g. 500K, 2M)")
+             .long("max-rate-per-file")
+             .value_name("RATE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("chunks")
+             .help("Download new files of 64 MiB or more as this many concurrent byte ranges, when the server supports it (at most 16 connections with --parallel-items)")
+             .long("chunks")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_concurrency)
+             .default_value("1"))
+        .arg(Arg::with_name("max-duration")
+             .help("Stop the whole run after this long (e.g. 90s, 30m, 2h), partial downloads resume next time")
+             .long("max-duration")
+             .value_name("DURATION")
+             .takes_value(true)
+             .validator(parse_duration))
+        .arg(Arg::with_name("retries")
+             .help("Retry a file this many times after a network error, timeout or server error")
+             .long("retries")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("3"))
+        .arg(Arg::with_name("retry-on-mismatch")
+             .help("Download a file again this many times when it fails checksum or size verification")
+             .long("retry-on-mismatch")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("0"))
+        .arg(Arg::with_name("max-total-retries")
+             .help("Abort the run once this many retries have been taken across all files (0 for no limit)")
+             .long("max-total-retries")
+             .value_name("N")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("0"))
+        .arg(Arg::with_name("simple-progress")
+             .help("Show progress as a single plain line updated in place instead of status messages, the final reports are still shown")
+             .long("simple-progress"))
+        .arg(Arg::with_name("summary-only")
+             .help("Hide progress and status messages, showing only the final report of each item and any errors")
+             .long("summary-only"))
+        .arg(Arg::with_name("progress-interval")
+             .help("Minimum milliseconds between progress updates (0 updates on every chunk)")
+             .long("progress-interval")
+             .value_name("MS")
+             .takes_value(true)
+             .validator(parse_count)
+             .default_value("0"))
+        .arg(Arg::with_name("hash-buffer")
+             .help("Read files through a buffer of this size while verifying them, the most memory hashing uses [default: 1MiB]")
+             .long("hash-buffer")
+             .value_name("SIZE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("checksum-source")
+             .help("Verify files without an MD5 in the metadata against a local MD5SUMS, SHA1SUMS or SHA256SUMS style file, or auto to use those in the item")
+             .long("checksum-source")
+             .value_name("FILE|auto")
+             .takes_value(true))
+        .arg(Arg::with_name("strict-checksum")
+             .help("Treat files without an MD5 in the metadata as failed, since they cannot be verified")
+             .long("strict-checksum"))
+        .arg(Arg::with_name("min-free-space")
+             .help("Stop before a download would leave less than this much free space (e.g. 10G)")
+             .long("min-free-space")
+             .value_name("SIZE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("verify-min-size")
+             .help("Check files smaller than this size (e.g. 64K) by size only instead of by checksum")
+             .long("verify-min-size")
+             .value_name("SIZE")
+             .takes_value(true)
+             .validator(parse_size))
+        .arg(Arg::with_name("verify-on-resume")
+             .help("Check existing local files against their checksum before keeping them, including those below --verify-min-size")
+             .long("verify-on-resume"))
+        .arg(Arg::with_name("overwrite")
+             .help("What to do with files that already exist locally")
+             .long("overwrite")
+             .value_name("POLICY")
+             .takes_value(true)
+             .possible_values(["always", "never", "if-different"])
+             .default_value("if-different"))
+        .arg(Arg::with_name("output-template")
+             .help("Output directory, may use {identifier}, {collection}, {creator}, {date} and {year}")
+             .long("output-template")
+             .value_name("TEMPLATE")
+             .takes_value(true))
+        .arg(Arg::with_name("template-fallback")
+             .help("Substituted for template fields missing from the item metadata")
+             .long("template-fallback")
+             .value_name("TEXT")
+             .takes_value(true)
+             .default_value("unknown"))
+        .arg(Arg::with_name("update-from")
+             .help("Only download files missing from, or a different size in, this reference directory")
+             .long("update-from")
+             .value_name("DIR")
+             .takes_value(true))
+        .arg(Arg::with_name("link-existing")
+             .help("Hardlink (or copy) files found in the --update-from directory into place")
+             .long("link-existing")
+             .requires("update-from"))
+        .arg(Arg::with_name("dedupe")
+             .help("Download only the first of several files with the same checksum")
+             .long("dedupe"))
+        .arg(Arg::with_name("link-dupes")
+             .help("Hardlink (or copy) skipped duplicates to the first downloaded copy")
+             .long("link-dupes")
+             .requires("dedupe"))
+        .arg(Arg::with_name("as-zip")
+             .help("Download the whole item as one zip made by archive.org, when no filters are active")
+             .long("as-zip"))
+        .arg(Arg::with_name("decompress")
+             .help("Extract downloaded archives into a directory named after each archive")
+             .long("decompress"))
+        .arg(Arg::with_name("decompress-formats")
+             .help("Archive formats to extract with --decompress, others are left as downloaded (comma separated)")
+             .long("decompress-formats")
+             .value_name("FORMATS")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true)
+             .possible_values(["zip", "gzip"])
+             .default_value("zip"))
+        .arg(Arg::with_name("keep-compressed")
+             .help("Keep each archive once --decompress has extracted it (the default)")
+             .long("keep-compressed")
+             .requires("decompress")
+             .overrides_with("remove-compressed"))
+        .arg(Arg::with_name("remove-compressed")
+             .help("Delete each archive once --decompress has extracted it, a later run downloads it again")
+             .long("remove-compressed")
+             .requires("decompress")
+             .overrides_with("keep-compressed"))
+        .arg(Arg::with_name("final-verify")
+             .help("Once an item is downloaded, check every file against its checksum again and report the item's integrity")
+             .long("final-verify"))
+        .arg(Arg::with_name("preserve-mtime")
+             .help("Set the modification time of downloaded files to the one in the metadata")
+             .long("preserve-mtime"))
+        .arg(Arg::with_name("read-only")
+             .help("Mark files read-only once they have been downloaded and verified")
+             .long("read-only"))
+        .arg(Arg::with_name("json")
+             .help("Print reports as JSON on stdout, status messages move to stderr")
+             .long("json"))
+        .arg(Arg::with_name("json-errors")
+             .help("Report errors on stderr as single line JSON objects with a kind, message and identifier")
+             .long("json-errors"))
+        .arg(Arg::with_name("diff")
+             .help("Compare the current XML metadata against a previous copy, then exit")
+             .long("diff"))
+        .arg(Arg::with_name("against")
+             .help("Previous XML metadata to compare with [default: the local <identifier>_files.xml]")
+             .long("against")
+             .value_name("FILE")
+             .takes_value(true)
+             .requires("diff"))
+        .arg(Arg::with_name("write-metadata-sidecar")
+             .help("Write the item metadata to ia-metadata.json and a summary to README.txt in the output directory")
+             .long("write-metadata-sidecar"))
+        .arg(Arg::with_name("health")
+             .help("Report missing checksums, unknown or zero sizes and case clashes, then exit")
+             .long("health"))
+        .arg(Arg::with_name("dry-run")
+             .help("Report which files would be downloaded, resumed, skipped or downloaded again, then exit")
+             .long("dry-run"))
+        .arg(Arg::with_name("format-report")
+             .help("Report the number and total size of the files in each format, then exit")
+             .long("format-report"))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Parse command line arguments
+    let matches = app().get_matches();
+
+    // Output modes come first, everything after may print
+    let json = matches.is_present("json");
+    MACHINE_OUTPUT.store(json, Ordering::Relaxed);
+    JSON_ERRORS.store(matches.is_present("json-errors"), Ordering::Relaxed);
+    SIMPLE_PROGRESS.store(matches.is_present("simple-progress"), Ordering::Relaxed);
+    SUMMARY_ONLY.store(matches.is_present("summary-only"), Ordering::Relaxed);
+    SI_UNITS.store(matches.value_of("units") == Some("si"), Ordering::Relaxed);
+    if matches.is_present("no-color") {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+    if let Some(size) = matches.value_of("hash-buffer").and_then(|size| parse_size(size).ok()) {
+        HASH_BUFFER_SIZE.store(size as usize, Ordering::Relaxed);
+    }
+    if let Err(e) = check_connection_count(&matches) {
+        let e: Box<dyn Error> = e.into();
+        report_error("Exiting", &*e, None);
+        process::exit(EXIT_INVALID_ARGS);
+    }
+    let ctx = Context::new(&matches).unwrap_or_else(|e| {
+        report_error("Exiting", &*e, None);
+        process::exit(exit_code(&*e));
+    });
+
+    if matches.is_present("search") {
+        let queries = match (matches.value_of("collection"), matches.value_of("queries")) {
+            (Some(collection), _) => vec![format!("collection:{}", collection)],
+            (None, Some(path)) => read_queries(path).unwrap_or_else(|e| {
+                let e: Box<dyn Error> = format!("failed to read queries from {}: {}", path, e).into();
+                report_error("Exiting", &*e, None);
+                process::exit(EXIT_INVALID_ARGS);
+            }),
+            (None, None) => unreachable!("the search group requires one of its arguments"),
+        };
+        let optional_count = |name: &str| -> Option<usize> {
+            matches.is_present(name).then(|| matches.value_of_t(name).unwrap_or_else(|e| e.exit()))
+        };
+        let searches = match ctx.within_deadline(run_searches(&ctx.client, &queries, optional_count("limit"), optional_count("max-items"))).await {
+            Ok(searches) => searches,
+            Err(e) => {
+                report_error("Exiting", &*e, None);
+                process::exit(exit_code(&*e));
+            }
+        };
+
+        if !matches.is_present("download") {
+            if json && matches.is_present("queries") {
+                println!("{}", serde_json::to_string_pretty(&searches)?);
+            } else if json {
+                println!("{}", serde_json::to_string_pretty(&searches[0].identifiers)?);
+            } else {
+                for identifier in searches.iter().flat_map(|search| &search.identifiers) {
+                    println!("{}", identifier);
+                }
+            }
+            return Ok(());
+        }
+        ctx.exit(download_items(&searches, &ctx).await);
+    }
+
+    if ctx.plans.len() > 1 {
+        let retries = Search {
+            query: "retry file".to_string(),
+            identifiers: ctx.plans.iter().map(|plan| plan.identifier.clone()).collect(),
+        };
+        ctx.exit(download_items(&[retries], &ctx).await);
+    }
+
+    if matches.is_present("list-collections") {
+        let item = matches.value_of("URL").ok_or("Missing URL argument")?;
+        match item_collections(&ctx.client, item).await {
+            Ok(collections) if json => println!("{}", serde_json::to_string_pretty(&collections)?),
+            Ok(collections) => {
+                for collection in collections {
+                    println!("{}", collection);
+                }
+            }
+            Err(e) => {
+                report_error("Exiting", &*e, Some(item));
+                process::exit(exit_code(&*e));
+            }
+        }
+        return Ok(());
+    }
+
+    // Several URLs are downloaded as a batch, a URL of - reads a list of
+    // identifiers or URLs from stdin
+    let urls: Vec<&str> = matches.values_of("URL").into_iter().flatten().collect();
+    if urls.len() > 1 || urls.contains(&"-") {
+        let mut identifiers = Vec::new();
+        for url in urls {
+            if url != "-" {
+                identifiers.push(url.to_string());
+                continue;
+            }
+            let text = std::io::read_to_string(std::io::stdin()).unwrap_or_else(|e| {
+                let e: Box<dyn Error> = format!("failed to read identifiers from stdin: {}", e).into();
+                report_error("Exiting", &*e, None);
+                process::exit(EXIT_INVALID_ARGS);
+            });
+            identifiers.extend(list_entries(&text));
+        }
+        let items = Search {
+            query: "command line".to_string(),
+            identifiers,
+        };
+        ctx.exit(download_items(&[items], &ctx).await);
+    }
+
+    let item = match ctx.plans.first() {
+        Some(plan) => plan.identifier.as_str(),
+        None => matches.value_of("URL").ok_or("Missing URL argument")?,
+    };
+    match ctx.within_deadline(download_item(item, None, &ctx)).await {
+        Ok(outcome) if !outcome.failed_files.is_empty() => ctx.exit(EXIT_CHECKSUM_FAILURE),
+        Ok(_) => ctx.exit(0),
+        Err(e) => {
+            report_error("Exiting", &*e, Some(item));
+            ctx.exit(exit_code(&*e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A fresh, empty directory for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ia-get-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// What the mock server answers to one request.
+    struct MockResponse {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    impl MockResponse {
+        /// `data`, or the part of it asked for by `range` when `ranges` are supported.
+        fn file(data: &[u8], range: Option<&str>, ranges: bool) -> Self {
+            let bounds = range.filter(|_| ranges).and_then(|range| {
+                let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+                let start: usize = start.parse().ok()?;
+                let end = end.parse::<usize>().map(|end| end + 1).unwrap_or(data.len()).min(data.len());
+                Some((start, end))
+            });
+            let mut headers = Vec::new();
+            if ranges {
+                headers.push(("Accept-Ranges", "bytes".to_string()));
+            }
+            match bounds {
+                Some((start, end)) => {
+                    headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end - 1, data.len())));
+                    MockResponse { status: 206, headers, body: data[start..end].to_vec() }
+                }
+                None => MockResponse { status: 200, headers, body: data.to_vec() },
+            }
+        }
+
+        fn status(status: u16) -> Self {
+            MockResponse { status, headers: Vec::new(), body: Vec::new() }
+        }
+    }
+
+    /// Serve HTTP on localhost, answering each request with `handler(method, path, range)`.
+    /// Returns the server's base URL.
+    async fn mock_server<F>(handler: F) -> reqwest::Url
+    where
+        F: Fn(&str, &str, Option<&str>) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = reqwest::Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let handler = std::sync::Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 4096];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match socket.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buffer[..read]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let mut words = request.split_whitespace();
+                    let (method, path) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+                    let range = request
+                        .lines()
+                        .find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("range")))
+                        .map(|(_, value)| value.trim());
+                    let response = handler(method, path, range);
+                    let mut head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+                    for (name, value) in &response.headers {
+                        head.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    head.push_str("\r\n");
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    if method != "HEAD" {
+                        let _ = socket.write_all(&response.body).await;
+                    }
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        url
+    }
+
+    /// Run `test` with a context built from the command line `args`.
+    async fn with_context<T>(args: &[&str], test: impl AsyncFnOnce(&Context<'_>) -> T) -> T {
+        let matches = app().get_matches_from([&["ia-get"], args].concat());
+        let ctx = Context::new(&matches).unwrap();
+        test(&ctx).await
+    }
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index * 7 % 251) as u8).collect()
+    }
+
+    /// An item's listing of a file holding `data`, with its size and MD5.
+    fn xml_file(name: &str, data: &[u8]) -> XmlFile {
+        XmlFile {
+            name: name.to_string(),
+            source: "original".to_string(),
+            mtime: None,
+            size: Some(data.len() as u64),
+            format: None,
+            rotation: None,
+            md5: Some(format!("{:x}", md5::compute(data))),
+            crc32: None,
+            sha1: None,
+            btih: None,
+            summation: None,
+            original: None,
+            old_version: None,
+        }
+    }
+
+    /// Download an item listing `files` into `dir`, with `args` added to the
+    /// command line. Each file is served with the contents given next to it,
+    /// unless `served` overrides what the server sends for that name.
+    async fn download_test_item(
+        dir: &Path,
+        files: Vec<XmlFile>,
+        served: Vec<(&str, Vec<u8>)>,
+        args: &[&str],
+    ) -> Result<ItemOutcome, Box<dyn Error>> {
+        let served: std::collections::HashMap<String, Vec<u8>> =
+            served.into_iter().map(|(name, data)| (format!("/download/item/{}", name), data)).collect();
+        let base = mock_server(move |_, path, range| match served.get(path) {
+            Some(data) => MockResponse::file(data, range, true),
+            None => MockResponse::status(404),
+        })
+        .await;
+        download_from(base, dir, files, args).await
+    }
+
+    /// Download an item listing `files` from the mock server at `base` into
+    /// `dir`, with `args` added to the command line.
+    async fn download_from(base: reqwest::Url, dir: &Path, files: Vec<XmlFile>, args: &[&str]) -> Result<ItemOutcome, Box<dyn Error>> {
+        let item_url = base.join("download/item/").unwrap();
+        let prepared = PreparedItem {
+            identifier: "item".to_string(),
+            xml_url: format!("{}item_files.xml", item_url),
+            item_url,
+            files: XmlFiles { files },
+            raw_metadata: None,
+        };
+        let args = [&["item", "--output-template", dir.to_str().unwrap()], args].concat();
+        with_context(&args, async |ctx| download_prepared(Vec::new(), Ok(prepared), None, ctx).await).await
+    }
+
+    fn download_url(file_name: &str) -> Result<String, IaGetError> {
+        let item_url = reqwest::Url::parse("https://archive.org/download/item/").unwrap();
+        construct_download_url(&item_url, file_name).map(String::from)
+    }
+
+    #[test]
+    fn download_url_encodes_each_segment() {
+        let cases = [
+            ("plain.txt", "https://archive.org/download/item/plain.txt"),
+            ("with space.mp3", "https://archive.org/download/item/with%20space.mp3"),
+            ("a+b.txt", "https://archive.org/download/item/a+b.txt"),
+            ("track #1.flac", "https://archive.org/download/item/track%20%231.flac"),
+            ("what?.txt", "https://archive.org/download/item/what%3F.txt"),
+            ("100%.txt", "https://archive.org/download/item/100%25.txt"),
+            ("%41.txt", "https://archive.org/download/item/%2541.txt"),
+            ("café/naïve.txt", "https://archive.org/download/item/caf%C3%A9/na%C3%AFve.txt"),
+            ("dir/sub/file.txt", "https://archive.org/download/item/dir/sub/file.txt"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(download_url(name).unwrap(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn download_url_refuses_parent_segments() {
+        for name in ["..", "../escape.txt", "a/../b", "a/.."] {
+            assert!(matches!(download_url(name), Err(IaGetError::UnsafePath { .. })), "{}", name);
+        }
+        assert!(download_url("..hidden/a..b").is_ok());
+    }
+
+    #[test]
+    fn identifier_validation_reports_each_problem() {
+        let long = "a".repeat(MAX_IDENTIFIER_LENGTH + 1);
+        let cases = [
+            ("", IdentifierError::Empty),
+            (long.as_str(), IdentifierError::TooLong { length: MAX_IDENTIFIER_LENGTH + 1 }),
+            ("bad item", IdentifierError::IllegalCharacter { character: ' ' }),
+            ("bad/item", IdentifierError::IllegalCharacter { character: '/' }),
+            ("café", IdentifierError::IllegalCharacter { character: 'é' }),
+            ("-item", IdentifierError::LeadingSeparator { character: '-' }),
+            (".item", IdentifierError::LeadingSeparator { character: '.' }),
+            ("item-", IdentifierError::TrailingSeparator { character: '-' }),
+            ("item.", IdentifierError::TrailingSeparator { character: '.' }),
+        ];
+        for (identifier, expected) in cases {
+            assert_eq!(validate_identifier(identifier), Err(expected), "{:?}", identifier);
+        }
+        let longest = "a".repeat(MAX_IDENTIFIER_LENGTH);
+        for identifier in ["item", "Item_2024", "_item_", "some.item-name", longest.as_str()] {
+            assert_eq!(validate_identifier(identifier), Ok(()), "{:?}", identifier);
+        }
+    }
+
+    #[test]
+    fn identifiers_are_extracted_from_urls() {
+        let cases = [
+            ("item", Ok("item")),
+            ("  item  ", Ok("item")),
+            ("https://archive.org/details/item", Ok("item")),
+            ("https://www.archive.org/details/item/", Ok("item")),
+            ("archive.org/details/item", Ok("item")),
+            ("https://archive.org/download/item/disc 1/track.mp3", Ok("item")),
+            ("https://archive.org/details/item?tab=about#reviews", Ok("item")),
+            ("https://web.archive.org/web/2020/https://example.com/", Err(IdentifierError::WaybackUrl)),
+            ("https://example.com/details/item", Err(IdentifierError::NotArchiveUrl { host: "example.com".to_string() })),
+            ("https://archive.org/search?query=cats", Err(IdentifierError::NoItemInUrl)),
+            ("https://archive.org/details/", Err(IdentifierError::NoItemInUrl)),
+            ("https://archive.org/details/-item", Err(IdentifierError::LeadingSeparator { character: '-' })),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(normalize_archive_identifier(input), expected.map(String::from), "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_download_joins_the_ranges() {
+        let data = test_data(100_000);
+        let served = data.clone();
+        let url = mock_server(move |_, _, range| MockResponse::file(&served, range, true)).await;
+        let dir = test_dir("chunked");
+        let part = dir.join("file.bin.part");
+        let throttle = Throttle { global: None, file: None };
+        with_context(&["item"], async |ctx| {
+            download_chunked(ctx, &url, &part, data.len() as u64, 7, &ProgressBar::hidden(), &throttle).await.unwrap();
+        })
+        .await;
+        assert_eq!(fs::read(&part).unwrap(), data);
+        assert!(!chunks_path(&part).exists());
+    }
+
+    #[tokio::test]
+    async fn interrupted_chunked_download_leaves_no_partial_file() {
+        let data = test_data(100_000);
+        let served = data.clone();
+        // The last range fails, after the others have been written
+        let url = mock_server(move |_, _, range| match range {
+            Some(range) if range.ends_with(&format!("-{}", served.len() - 1)) => MockResponse::status(503),
+            _ => MockResponse::file(&served, range, true),
+        })
+        .await;
+        let dir = test_dir("chunked-interrupted");
+        let part = dir.join("file.bin.part");
+        let throttle = Throttle { global: None, file: None };
+        let result = with_context(&["item"], async |ctx| {
+            download_chunked(ctx, &url, &part, data.len() as u64, 4, &ProgressBar::hidden(), &throttle).await
+        })
+        .await;
+        assert!(result.is_err());
+        assert!(!part.exists(), "a partial file with gaps would be resumed as if it were complete");
+        assert!(!chunks_path(&part).exists());
+    }
+
+    /// Resume a partial download holding `partial` from a server that does or does not support ranges.
+    async fn resume_stream(partial: &[u8], ranges: bool) -> (Vec<u8>, u64, Vec<Option<String>>) {
+        let data = test_data(10_000);
+        let served = data.clone();
+        let requested = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = requested.clone();
+        let url = mock_server(move |_, _, range| {
+            log.lock().unwrap().push(range.map(str::to_string));
+            MockResponse::file(&served, range, ranges)
+        })
+        .await;
+        let part = test_dir(&format!("resume-{}", ranges)).join("file.bin.part");
+        fs::write(&part, partial).unwrap();
+        let mut position = partial.len() as u64;
+        let throttle = Throttle { global: None, file: None };
+        with_context(&["item"], async |ctx| {
+            download_stream(ctx, &url, &part, &mut position, &ProgressBar::hidden(), &throttle, Duration::ZERO).await.unwrap();
+        })
+        .await;
+        assert_eq!(fs::read(&part).unwrap(), data);
+        let requested = requested.lock().unwrap().clone();
+        (data, position, requested)
+    }
+
+    #[tokio::test]
+    async fn stream_resumes_from_the_partial_file() {
+        let (data, position, requested) = resume_stream(&test_data(10_000)[..4_000], true).await;
+        assert_eq!(position, data.len() as u64);
+        assert_eq!(requested, [Some("bytes=4000-".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn stream_restarts_when_the_range_is_ignored() {
+        // Were the whole file appended to the partial one, it would be 4,000 bytes too long
+        let (data, position, _) = resume_stream(&test_data(10_000)[..4_000], false).await;
+        assert_eq!(position, data.len() as u64);
+    }
+
+    /// A server that accepts connections but never answers. Returns the URL of a file on it.
+    async fn silent_server() -> reqwest::Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = reqwest::Url::parse(&format!("http://{}/file.bin", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn stream_times_out_waiting_for_the_response() {
+        let url = silent_server().await;
+        let part = test_dir("response-timeout").join("file.bin.part");
+        let throttle = Throttle { global: None, file: None };
+        let result = with_context(&["item", "--read-timeout", "1"], async |ctx| {
+            let mut position = 0;
+            download_stream(ctx, &url, &part, &mut position, &ProgressBar::hidden(), &throttle, Duration::ZERO).await
+        })
+        .await;
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref::<IaGetError>(), Some(IaGetError::ReadTimeout { seconds: 1 })), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn responses_map_to_their_error_kind() {
+        let base = mock_server(|_, path, _| MockResponse::status(path.trim_start_matches('/').parse().unwrap())).await;
+        let cases = [
+            (404, "not_found", "not found (HTTP 404)"),
+            (401, "forbidden", "access forbidden (HTTP 401)"),
+            (403, "forbidden", "access forbidden (HTTP 403)"),
+            (503, "server_error", "server error (HTTP 503)"),
+            (418, "http_status", "unexpected response (HTTP 418)"),
+        ];
+        for (status, kind, message) in cases {
+            let response = Client::new().get(base.join(&status.to_string()).unwrap()).send().await.unwrap();
+            let error: Box<dyn Error> = check_response(response).unwrap_err().into();
+            assert_eq!(error_kind(&*error), kind);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn gzipped_metadata_is_decoded() {
+        let xml = "<files><file name=\"data.bin\" source=\"original\"><size>3000</size></file></files>";
+        let gzipped = gzip_data(xml.as_bytes());
+        let base = mock_server(move |_, path, _| match path {
+            "/gzip.xml" => MockResponse {
+                status: 200,
+                headers: vec![("Content-Encoding", "gzip".to_string())],
+                body: gzipped.clone(),
+            },
+            _ => MockResponse::file(xml.as_bytes(), None, false),
+        })
+        .await;
+        let mut log = Vec::new();
+        let fetched = fetch_xml(&Client::new(), base.join("gzip.xml").unwrap().as_str(), true, true, &mut log).await.unwrap();
+        assert_eq!(fetched, xml);
+        assert!(log[0].contains("(gzip: "), "{}", log[0]);
+        let fetched = fetch_xml(&Client::new(), base.join("plain.xml").unwrap().as_str(), false, true, &mut log).await.unwrap();
+        assert_eq!(fetched, xml);
+        assert!(log[1].contains("(uncompressed)"), "{}", log[1]);
+    }
+
+    #[tokio::test]
+    async fn redirects_only_reach_allowed_hosts() {
+        let data = test_data(3000);
+        let served = data.clone();
+        let base = mock_server(move |_, path, range| match path {
+            "/download/item/foreign.bin" => MockResponse {
+                status: 302,
+                headers: vec![("Location", "http://foreign.invalid/data.bin".to_string())],
+                body: Vec::new(),
+            },
+            "/download/item/local.bin" => MockResponse {
+                status: 302,
+                headers: vec![("Location", "/stored/data.bin".to_string())],
+                body: Vec::new(),
+            },
+            "/stored/data.bin" => MockResponse::file(&served, range, true),
+            _ => MockResponse::status(404),
+        })
+        .await;
+
+        let dir = test_dir("redirect-foreign");
+        let result = download_from(base.clone(), &dir, vec![xml_file("foreign.bin", &data)], &[]).await;
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref::<IaGetError>(), Some(IaGetError::RedirectNotAllowed { .. })), "{}", error);
+        assert_eq!(error_kind(&*error), "redirect_not_allowed");
+        assert!(!dir.join("foreign.bin").exists());
+
+        // The mock server's own host has to be allowed explicitly, it is not archive.org
+        let dir = test_dir("redirect-allowed");
+        let files = vec![xml_file("local.bin", &data)];
+        let outcome = download_from(base, &dir, files, &["--allow-redirect-host", "127.0.0.1"]).await.unwrap();
+        assert!(outcome.failed_files.is_empty());
+        assert_eq!(fs::read(dir.join("local.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_slow_downloads_to_the_cap() {
+        let (first, second) = (test_data(12_000), test_data(12_001).split_off(1));
+        let files = || vec![xml_file("first.bin", &first), xml_file("second.bin", &second)];
+        let served = || vec![("first.bin", first.clone()), ("second.bin", second.clone())];
+        // 24,000 bytes at 24,000 bytes per second overall, or one after the other at 12,000 each
+        for (args, seconds) in [(["--max-rate", "24000"], 1.0), (["--max-rate-per-file", "12000"], 2.0)] {
+            let dir = test_dir("rate-limit");
+            let started = Instant::now();
+            let outcome = download_test_item(&dir, files(), served(), &args).await.unwrap();
+            let elapsed = started.elapsed();
+            assert!(outcome.failed_files.is_empty(), "{:?}", args);
+            assert_eq!(fs::read(dir.join("second.bin")).unwrap(), second);
+            assert!(elapsed >= Duration::from_secs_f64(seconds * 0.9), "{:?} took {:?}", args, elapsed);
+        }
+        let dir = test_dir("rate-unlimited");
+        let started = Instant::now();
+        download_test_item(&dir, files(), served(), &[]).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn retry_delay_backs_off_and_caps_retry_after() {
+        let server_error = IaGetError::ServerError { status: 503 };
+        let delays: Vec<u64> = (1..=7).map(|attempt| retry_delay(&server_error, attempt).as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 16, 32, 32, 32]);
+        let rate_limited = |seconds| IaGetError::RateLimited { retry_after: Some(seconds) };
+        assert_eq!(retry_delay(&rate_limited(7), 1), Duration::from_secs(7));
+        assert_eq!(retry_delay(&rate_limited(86_400), 1), MAX_RETRY_AFTER);
+        assert_eq!(retry_delay(&IaGetError::RateLimited { retry_after: None }, 2), Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn max_duration_stops_a_stalled_request() {
+        let url = silent_server().await;
+        let part = test_dir("max-duration").join("file.bin.part");
+        let throttle = Throttle { global: None, file: None };
+        let result = with_context(&["item", "--read-timeout", "0", "--max-duration", "1s"], async |ctx| {
+            let mut position = 0;
+            ctx.within_deadline(download_stream(ctx, &url, &part, &mut position, &ProgressBar::hidden(), &throttle, Duration::ZERO))
+                .await
+        })
+        .await;
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref::<IaGetError>(), Some(IaGetError::TimedOut { .. })), "{}", error);
+        assert_eq!(exit_code(&*error), EXIT_TIMED_OUT);
+    }
+
+    #[tokio::test]
+    async fn max_duration_stops_a_retry_sleep() {
+        let started = Instant::now();
+        let result = with_context(&["item", "--max-duration", "1s"], async |ctx| {
+            ctx.within_deadline(async {
+                tokio::time::sleep(retry_delay(&IaGetError::RateLimited { retry_after: Some(60) }, 1)).await;
+                Ok(())
+            })
+            .await
+        })
+        .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn concurrency_options_are_capped() {
+        assert_eq!(parse_concurrency("1"), Ok(1));
+        assert_eq!(parse_concurrency(" 16 "), Ok(MAX_CONCURRENCY));
+        for value in ["0", "17", "1000", "-1", "four", ""] {
+            assert!(parse_concurrency(value).is_err(), "{:?}", value);
+        }
+        assert!(app().try_get_matches_from(["ia-get", "item", "--chunks", "64"]).is_err());
+        assert!(app().try_get_matches_from(["ia-get", "item", "--chunks", "8"]).is_ok());
+    }
+
+    #[test]
+    fn counts_must_be_whole_numbers() {
+        let options = [
+            "--retries",
+            "--retry-on-mismatch",
+            "--max-total-retries",
+            "--max-files",
+            "--progress-interval",
+            "--limit",
+            "--max-items",
+            "--connect-timeout",
+            "--read-timeout",
+            "--timeout",
+        ];
+        // --limit and --max-items only apply to --queries
+        for option in options {
+            for value in ["-1", "1.5", "lots", ""] {
+                let args = ["ia-get", "--queries", "queries.txt", option, value];
+                assert!(app().try_get_matches_from(args).is_err(), "{} {:?}", option, value);
+            }
+            assert!(app().try_get_matches_from(["ia-get", "--queries", "queries.txt", option, "0"]).is_ok(), "{}", option);
+        }
+    }
+
+    /// Draw target that keeps what is drawn, for checking progress lines.
+    #[derive(Debug, Default, Clone)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl indicatif::TermLike for Recorder {
+        fn width(&self) -> u16 {
+            u16::MAX
+        }
+
+        fn move_cursor_up(&self, _n: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_down(&self, _n: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_right(&self, _n: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_left(&self, _n: usize) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn write_line(&self, s: &str) -> std::io::Result<()> {
+            self.write_str(s)
+        }
+
+        fn write_str(&self, s: &str) -> std::io::Result<()> {
+            if !s.trim().is_empty() {
+                self.0.lock().unwrap().push(s.to_string());
+            }
+            Ok(())
+        }
+
+        fn clear_line(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn simple_progress_line_is_plain_text() {
+        let recorder = Recorder::default();
+        let pb = ProgressBar::with_draw_target(Some(2048), ProgressDrawTarget::term_like(Box::new(recorder.clone())));
+        pb.set_style(simple_progress_style());
+        pb.set_message("12.5% overall, file 2/8 disc 1/track.flac: ");
+        pb.set_position(1024);
+        pb.abandon();
+        let lines = recorder.0.lock().unwrap();
+        let line = lines.last().expect("the progress line was drawn");
+        assert!(line.starts_with("12.5% overall, file 2/8 disc 1/track.flac: 50% of 2"), "{}", line);
+        assert!(line.contains(" at ") && line.contains(", ETA "), "{}", line);
+        assert!(line.is_ascii(), "{}", line);
+    }
+
+    /// Held by tests that change `--simple-progress` or `--summary-only`, or depend on the default.
+    static OUTPUT_MODES: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[test]
+    fn summary_only_and_simple_progress_combine() {
+        let _modes = OUTPUT_MODES.blocking_lock();
+        assert!(app().try_get_matches_from(["ia-get", "item", "--summary-only", "--simple-progress"]).is_ok());
+        let modes = [(false, false, true), (true, false, false), (false, true, false), (true, true, false)];
+        for (simple_progress, summary_only, shown) in modes {
+            SIMPLE_PROGRESS.store(simple_progress, Ordering::Relaxed);
+            SUMMARY_ONLY.store(summary_only, Ordering::Relaxed);
+            assert_eq!(status_shown(), shown, "simple {} summary {}", simple_progress, summary_only);
+            if simple_progress || summary_only {
+                assert_eq!(progress_bar(100, "").is_hidden(), summary_only, "simple {} summary {}", simple_progress, summary_only);
+            }
+        }
+        SIMPLE_PROGRESS.store(false, Ordering::Relaxed);
+        SUMMARY_ONLY.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn several_identifiers_are_accepted() {
+        let matches = app().get_matches_from(["ia-get", "first", "https://archive.org/details/second", "-", "--parallel-items", "3"]);
+        let items: Vec<&str> = matches.values_of("URL").unwrap().collect();
+        assert_eq!(items, ["first", "https://archive.org/details/second", "-"]);
+        assert_eq!(matches.value_of_t::<usize>("parallel-items").unwrap(), 3);
+        for option in ["--parallel-items", "--metadata-jobs"] {
+            assert!(app().try_get_matches_from(["ia-get", "first", "second", option, "17"]).is_err(), "{}", option);
+            assert!(app().try_get_matches_from(["ia-get", "first", "second", option, "0"]).is_err(), "{}", option);
+        }
+    }
+
+    #[test]
+    fn parallel_items_and_chunks_share_the_connection_cap() {
+        let check = |parallel_items: &str, chunks: &str| {
+            let matches = app().get_matches_from(["ia-get", "first", "second", "--parallel-items", parallel_items, "--chunks", chunks]);
+            check_connection_count(&matches)
+        };
+        assert!(check("1", "16").is_ok());
+        assert!(check("16", "1").is_ok());
+        assert!(check("4", "4").is_ok());
+        assert!(check("2", "16").is_err());
+        assert_eq!(check("5", "4").unwrap_err(), "--parallel-items 5 with --chunks 4 could open 20 connections at once, at most 16 are allowed");
+    }
+
+    #[test]
+    fn many_files_verify_with_one_blocking_thread() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let dir = test_dir("blocking-pool");
+        let mut contents: Vec<(String, Vec<u8>)> = (0..24).map(|index| (format!("file{}.bin", index), test_data(1000 + index))).collect();
+        contents.push(("notes.txt.gz".to_string(), gzip_data(&test_data(5000))));
+        let files = contents.iter().map(|(name, data)| xml_file(name, data)).collect();
+        let served = contents.iter().map(|(name, data)| (name.as_str(), data.clone())).collect();
+        let outcome = runtime.block_on(download_test_item(&dir, files, served, &["--decompress", "--decompress-formats", "gzip"])).unwrap();
+        assert!(outcome.failed_files.is_empty(), "{:?}", outcome.failed_files);
+        for (name, data) in &contents {
+            assert_eq!(&fs::read(dir.join(name)).unwrap(), data, "{}", name);
+        }
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), test_data(5000));
+    }
+
+    #[tokio::test]
+    async fn clashing_names_are_not_written_over() {
+        let dir = test_dir("name-clashes");
+        let (plain, part) = (test_data(3000), test_data(2000));
+        let files = vec![xml_file("data.bin", &plain), xml_file("data.bin.part", &part), xml_file("data.bin", &part)];
+        let clashes = name_clashes(&files);
+        assert_eq!(clashes, [(1, 0), (2, 0)]);
+        let served = vec![("data.bin", plain.clone()), ("data.bin.part", part)];
+        let outcome = download_test_item(&dir, files, served, &[]).await.unwrap();
+        assert!(outcome.failed_files.is_empty(), "{:?}", outcome.failed_files);
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), plain);
+        assert!(!dir.join("data.bin.part").exists());
+        assert!(orphaned_parts(&dir, &[xml_file("data.bin.part", &[])]).is_empty());
+    }
+
+    #[test]
+    fn orphan_scan_stays_in_the_output_directory() {
+        let dir = test_dir("orphans-confined");
+        let output_dir = dir.join("item");
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(dir.join("elsewhere.bin.part"), b"partial").unwrap();
+        fs::write(output_dir.join("stray.bin.part"), b"partial").unwrap();
+        let files = [xml_file("../outside.bin", &[]), xml_file("kept.bin", &[])];
+        assert_eq!(orphaned_parts(&output_dir, &files), [output_dir.join("stray.bin.part")]);
+    }
+
+    fn gzip_data(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip_data(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn only_listed_archive_formats_are_extracted() {
+        let dir = test_dir("decompress-formats");
+        let (zip, gzip) = (zip_data("inner.txt", &test_data(100)), gzip_data(&test_data(200)));
+        let files = vec![xml_file("bundle.zip", &zip), xml_file("notes.txt.gz", &gzip)];
+        let served = vec![("bundle.zip", zip.clone()), ("notes.txt.gz", gzip)];
+        download_test_item(&dir, files, served, &["--decompress", "--decompress-formats", "gzip"]).await.unwrap();
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), test_data(200));
+        assert_eq!(fs::read(dir.join("bundle.zip")).unwrap(), zip);
+        assert!(!dir.join("bundle").exists());
+    }
+
+    #[tokio::test]
+    async fn extraction_never_writes_over_item_files() {
+        let dir = test_dir("decompress-clash");
+        let (gzip, zip, plain) = (gzip_data(&test_data(200)), zip_data("inner.txt", &test_data(100)), test_data(50));
+        let files = vec![xml_file("notes.txt.gz", &gzip), xml_file("notes.txt", &plain), xml_file("bundle.zip", &zip), xml_file("bundle/cover.jpg", &plain)];
+        let served = vec![("notes.txt.gz", gzip), ("notes.txt", plain.clone()), ("bundle.zip", zip), ("bundle/cover.jpg", plain.clone())];
+        download_test_item(&dir, files, served, &["--decompress", "--decompress-formats", "zip,gzip"]).await.unwrap();
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), plain);
+        assert_eq!(fs::read(dir.join("bundle/cover.jpg")).unwrap(), plain);
+        assert!(!dir.join("bundle/inner.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn extracted_archives_are_kept_unless_removal_is_asked_for() {
+        let gzip = gzip_data(&test_data(200));
+        let policies: [(&[&str], bool); 3] =
+            [(&["--decompress"], true), (&["--decompress", "--keep-compressed"], true), (&["--decompress", "--remove-compressed"], false)];
+        for (args, kept) in policies {
+            let dir = test_dir("decompress-policy");
+            let log = dir.join("events.jsonl");
+            let files = vec![xml_file("notes.txt.gz", &gzip)];
+            let args = [args, &["--decompress-formats", "gzip", "--event-log", log.to_str().unwrap(), "--final-verify"]].concat();
+            let outcome = download_test_item(&dir, files, vec![("notes.txt.gz", gzip.clone())], &args).await.unwrap();
+            assert!(outcome.failed_files.is_empty(), "{:?}", args);
+            assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), test_data(200));
+            assert_eq!(dir.join("notes.txt.gz").exists(), kept, "{:?}", args);
+            let records: Vec<serde_json::Value> =
+                fs::read_to_string(&log).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+            let extracted = records.iter().find(|record| record["event"] == "file_extracted").unwrap();
+            assert_eq!(extracted["compressed"], if kept { "kept" } else { "removed" });
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_extraction_keeps_the_archive() {
+        let dir = test_dir("decompress-remove-failed");
+        // Valid gzip framing around a corrupted body fails its CRC check
+        let mut gzip = gzip_data(&test_data(20_000));
+        let middle = gzip.len() / 2;
+        gzip[middle] ^= 0xff;
+        let files = vec![xml_file("notes.txt.gz", &gzip)];
+        let args = ["--decompress", "--decompress-formats", "gzip", "--remove-compressed"];
+        download_test_item(&dir, files, vec![("notes.txt.gz", gzip.clone())], &args).await.unwrap();
+        assert_eq!(fs::read(dir.join("notes.txt.gz")).unwrap(), gzip);
+        assert!(!dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn failed_extraction_leaves_nothing_behind() {
+        let dir = test_dir("decompress-failed");
+        let gzip = gzip_data(&test_data(20_000));
+        let archive = dir.join("notes.txt.gz");
+        fs::write(&archive, &gzip[..gzip.len() / 2]).unwrap();
+        assert!(extract_archive(&archive, ArchiveFormat::Gzip).is_err());
+        assert!(!dir.join("notes.txt").exists());
+        assert!(!dir.join("notes.txt.part").exists());
+
+        let archive = dir.join("bundle.zip");
+        fs::write(&archive, zip_data("inner.txt", &test_data(100))).unwrap();
+        fs::create_dir_all(dir.join("bundle")).unwrap();
+        fs::write(dir.join("bundle/stale.txt"), b"stale").unwrap();
+        assert_eq!(extract_archive(&archive, ArchiveFormat::Zip).unwrap(), (dir.join("bundle"), 1));
+        assert_eq!(fs::read(dir.join("bundle/inner.txt")).unwrap(), test_data(100));
+        assert!(!dir.join("bundle/stale.txt").exists());
+        assert!(!dir.join("bundle.part").exists());
+    }
+
+    #[test]
+    fn metadata_sidecar_avoids_item_file_names() {
+        let dir = test_dir("sidecar");
+        let raw = serde_json::json!({
+            "metadata": {
+                "title": "A Title",
+                "creator": ["First", "Second"],
+                "date": "1999-12-31",
+                "description": "<p>Some <b>bold</b> words</p>",
+            }
+        });
+        let files = vec![xml_file("readme.TXT", &test_data(10)), xml_file("track.flac", &test_data(2048))];
+        let metadata_path = write_metadata_sidecar(&dir, "item", &raw, &files).unwrap();
+        assert_eq!(metadata_path, dir.join("ia-metadata.json"));
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&metadata_path).unwrap()).unwrap();
+        assert_eq!(written, raw);
+        assert!(!dir.join("README.txt").exists());
+        let readme = fs::read_to_string(dir.join("item_ia-get_README.txt")).unwrap();
+        assert!(readme.starts_with("A Title\n\n"), "{}", readme);
+        for line in ["Identifier:  item", "Creator:     First; Second", "Date:        1999-12-31", "Files:       2", "Some bold words"] {
+            assert!(readme.contains(line), "{} not in {}", line, readme);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn linked_files_are_copied_before_they_change() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir("link-existing");
+        let reference = test_dir("link-existing-reference");
+        let data = test_data(4000);
+        fs::write(reference.join("data.bin"), &data).unwrap();
+        let reference_dir = reference.to_str().unwrap();
+        let args = ["--update-from", reference_dir, "--link-existing", "--read-only"];
+        download_test_item(&dir, vec![xml_file("data.bin", &data)], Vec::new(), &args).await.unwrap();
+        assert_eq!(fs::metadata(dir.join("data.bin")).unwrap().ino(), fs::metadata(reference.join("data.bin")).unwrap().ino());
+
+        // The second run marks the file read-only, which must not reach the reference copy
+        download_test_item(&dir, vec![xml_file("data.bin", &data)], Vec::new(), &args).await.unwrap();
+        assert!(fs::metadata(dir.join("data.bin")).unwrap().permissions().readonly());
+        assert!(!fs::metadata(reference.join("data.bin")).unwrap().permissions().readonly());
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), data);
+
+        let linked = dir.join("linked.bin");
+        fs::hard_link(reference.join("data.bin"), &linked).unwrap();
+        break_hardlink(&linked).unwrap();
+        fs::write(&linked, b"changed").unwrap();
+        assert_eq!(fs::read(reference.join("data.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn dry_run_classifies_local_copies_and_partial_downloads() {
+        let dir = test_dir("dry-run");
+        let data = test_data(3000);
+        let files: Vec<XmlFile> = ["new.bin", "same.bin", "partial.bin", "changed.bin", "oversized.bin"]
+            .iter()
+            .map(|name| xml_file(name, &data))
+            .collect();
+        fs::write(dir.join("same.bin"), &data).unwrap();
+        fs::write(dir.join("partial.bin.part"), &data[..1000]).unwrap();
+        fs::write(dir.join("changed.bin"), test_data(3001).split_off(1)).unwrap();
+        fs::write(dir.join("oversized.bin.part"), test_data(4000)).unwrap();
+        let report = dry_run_report(&files, &dir, OverwritePolicy::IfDifferent, 0).await.unwrap();
+        assert_eq!(report.new, ["new.bin", "oversized.bin"]);
+        assert_eq!(report.skip, ["same.bin"]);
+        assert_eq!(report.resume, ["partial.bin"]);
+        assert_eq!(report.redownload, ["changed.bin"]);
+
+        let report = dry_run_report(&files, &dir, OverwritePolicy::Always, 0).await.unwrap();
+        assert!(report.resume.is_empty());
+
+        let files = vec![xml_file("../outside.bin", &data), xml_file("/absolute.bin", &data), xml_file("same.bin", &data)];
+        let report = dry_run_report(&files, &dir, OverwritePolicy::IfDifferent, 0).await.unwrap();
+        assert_eq!(report.excluded, ["../outside.bin", "/absolute.bin"]);
+        assert_eq!(report.skip, ["same.bin"]);
+    }
+
+    #[tokio::test]
+    async fn verify_on_resume_hashes_small_local_files() {
+        let dir = test_dir("verify-on-resume");
+        let data = test_data(3000);
+        let mut corrupt = data.clone();
+        corrupt[1500] ^= 0xff;
+        fs::write(dir.join("data.bin"), &corrupt).unwrap();
+        // Below --verify-min-size a file of the right size is trusted
+        let args = ["--verify-min-size", "1M"];
+        download_test_item(&dir, vec![xml_file("data.bin", &data)], vec![("data.bin", data.clone())], &args).await.unwrap();
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), corrupt);
+
+        let args = ["--verify-min-size", "1M", "--verify-on-resume"];
+        let outcome = download_test_item(&dir, vec![xml_file("data.bin", &data)], vec![("data.bin", data.clone())], &args).await.unwrap();
+        assert!(outcome.failed_files.is_empty());
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), data);
+    }
+
+    /// The names `filter_files` keeps from `names` under the command line `args`.
+    async fn filtered_names(names: &[&str], args: &[&str]) -> Vec<String> {
+        let files: Vec<XmlFile> = names.iter().map(|name| xml_file(name, &[])).collect();
+        with_context(&[&["item"], args].concat(), async |ctx| filter_files(files, &ctx.filters)).await.into_iter().map(|file| file.name).collect()
+    }
+
+    #[tokio::test]
+    async fn preferred_formats_compete_after_the_other_filters() {
+        let names = ["track.flac", "track.mp3", "track.ogg", "cover.jpg", "cover.png"];
+        assert_eq!(filtered_names(&names, &["--prefer-format", "flac,mp3,png,jpg"]).await, ["track.flac", "track.ogg", "cover.png"]);
+        assert_eq!(
+            filtered_names(&names, &["--prefer-format", "flac,mp3,png,jpg", "--exclude-ext", "flac,png"]).await,
+            ["track.mp3", "track.ogg", "cover.jpg"]
+        );
+        assert_eq!(filtered_names(&names, &["--prefer-format", "flac,mp3", "--include-ext", "mp3"]).await, ["track.mp3"]);
+
+        let files: Vec<XmlFile> = names.iter().map(|name| xml_file(name, &[])).collect();
+        with_context(&["item", "--prefer-format", "flac,mp3", "--exclude-ext", "ogg"], async |ctx| {
+            let reason = |name| explain_selection(&files, &ctx.filters, None, None, name).reason;
+            assert_eq!(reason("track.mp3"), "--prefer-format keeps track.flac instead");
+            assert_eq!(reason("track.ogg"), "excluded by --exclude-ext ogg");
+            assert_eq!(reason("track.flac"), "included");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn format_report_groups_files_without_downloading() {
+        let dir = test_dir("format-report");
+        let mut files = vec![
+            xml_file("a.mp3", &test_data(5000)),
+            xml_file("b.mp3", &test_data(3000)),
+            xml_file("cover.jpg", &test_data(2000)),
+            xml_file("blob.xyz", &test_data(100)),
+            xml_file("notes.txt", &test_data(100)),
+        ];
+        files[0].format = Some("VBR MP3".to_string());
+        files[1].format = Some("VBR MP3".to_string());
+        let report = format_report(&files);
+        let summary: Vec<(&str, usize, u64)> =
+            report.iter().map(|format| (format.format.as_str(), format.files, format.total_bytes)).collect();
+        assert_eq!(summary, [("VBR MP3", 2, 8000), ("JPEG", 1, 2000), ("Text", 1, 100), ("Unknown", 1, 100)]);
+
+        // The report ends the item before anything is requested
+        let outcome = download_test_item(&dir, files, Vec::new(), &["--format-report", "--exclude-ext", "txt"]).await.unwrap();
+        assert!(outcome.failed_files.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn health_report_flags_each_problem_once() {
+        let mut files: Vec<XmlFile> = ["a.txt", "b.txt", "c.txt", "d.txt", "A.TXT"].iter().map(|name| xml_file(name, b"data")).collect();
+        files[1].md5 = None;
+        files[2].size = None;
+        files[3].size = Some(0);
+        // Several problems with one file count against the score only once
+        files[2].md5 = None;
+        let report = health_report(&files);
+        assert_eq!(report.total_files, 5);
+        assert_eq!(report.missing_checksums, ["b.txt", "c.txt"]);
+        assert_eq!(report.unknown_sizes, ["c.txt"]);
+        assert_eq!(report.zero_byte_files, ["d.txt"]);
+        assert_eq!(report.case_collisions, [("a.txt".to_string(), "A.TXT".to_string())]);
+        assert_eq!(report.completeness_score, 20.0);
+        assert_eq!(health_report(&[]).completeness_score, 0.0);
+    }
+
+    #[test]
+    fn metadata_diff_finds_added_removed_and_modified_files() {
+        let with_mtime = |name: &str, data: &[u8], mtime: &str| XmlFile { mtime: Some(mtime.to_string()), ..xml_file(name, data) };
+        let old = [
+            with_mtime("same.txt", b"same", "100"),
+            with_mtime("gone.txt", b"gone", "100"),
+            with_mtime("grown.txt", b"grown", "100"),
+            with_mtime("touched.txt", b"touched", "100"),
+        ];
+        let new = [
+            // Fractions of a second are not a change
+            with_mtime("same.txt", b"same", "100.75"),
+            with_mtime("grown.txt", b"grown, and more", "100"),
+            with_mtime("touched.txt", b"touched", "200"),
+            with_mtime("added.txt", b"added", "300"),
+        ];
+        let diff = diff_metadata(&old, &new);
+        assert_eq!(diff.added, ["added.txt"]);
+        assert_eq!(diff.removed, ["gone.txt"]);
+        let modified: Vec<&str> = diff.modified.iter().map(|change| change.name.as_str()).collect();
+        assert_eq!(modified, ["grown.txt", "touched.txt"]);
+        assert_eq!((diff.modified[0].old_size, diff.modified[0].new_size), (Some(5), Some(15)));
+        assert_ne!(diff.modified[0].old_md5, diff.modified[0].new_md5);
+        assert_eq!((diff.modified[1].old_mtime, diff.modified[1].new_mtime), (Some(100), Some(200)));
+        assert_eq!(format_size_delta(5, 15), "+10 B");
+        assert_eq!(format_size_delta(15, 5), "-10 B");
+    }
+
+    #[test]
+    fn output_template_expands_placeholders() {
+        let fields: ItemFields = serde_json::from_value(serde_json::json!({
+            "collection": ["opensource_audio", "community"],
+            "creator": "AC/DC",
+            "date": "1979-07-27",
+        }))
+        .unwrap();
+        let render = |template: &str| render_output_template(template, "item", &fields, "unknown");
+        assert_eq!(render("{collection}/{year}/{identifier}").unwrap(), PathBuf::from("opensource_audio/1979/item"));
+        assert_eq!(render("{creator} - {date}").unwrap(), PathBuf::from("AC_DC - 1979-07-27"));
+        assert_eq!(render("downloads/{identifier}").unwrap(), PathBuf::from("downloads/item"));
+        assert!(render("{title}").unwrap_err().contains("{title}"));
+        assert!(render("{identifier").unwrap_err().contains("Unterminated"));
+
+        let undated: ItemFields = serde_json::from_value(serde_json::json!({ "date": "circa 1900" })).unwrap();
+        let render = |template: &str| render_output_template(template, "item", &undated, "unknown");
+        assert_eq!(render("{creator}/{year}").unwrap(), PathBuf::from("unknown/unknown"));
+        assert!(template_needs_metadata("{year}/{identifier}"));
+        assert!(!template_needs_metadata("{identifier}"));
+    }
+
+    /// Held by tests that change `--units`, or depend on the default.
+    static UNITS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[test]
+    fn sizes_follow_the_chosen_units() {
+        let _units = UNITS.blocking_lock();
+        let parse = |value: &str| parse_size(value).unwrap();
+        for si in [false, true] {
+            SI_UNITS.store(si, Ordering::Relaxed);
+            let kilo = if si { 1000 } else { 1024 };
+            assert_eq!(parse("512"), 512);
+            assert_eq!(parse("500K"), 500 * kilo);
+            assert_eq!(parse("1.5 MB"), 3 * kilo * kilo / 2);
+            assert_eq!(parse("2GiB"), 2 << 30);
+            assert_eq!(parse("1kib"), 1024);
+            assert!(parse_size("fast").is_err());
+            assert!(parse_size("5X").is_err());
+        }
+        assert_eq!(format_size(1_048_576), "1.05 MB");
+        SI_UNITS.store(false, Ordering::Relaxed);
+        assert_eq!(format_size(1_048_576), "1.00 MiB");
+        assert_eq!(format_size(512), "512 B");
+    }
+
+    #[tokio::test]
+    async fn only_verified_downloads_take_their_final_name() {
+        let dir = test_dir("atomic-rename");
+        let (good, bad) = (test_data(3000), test_data(2000));
+        let files = vec![xml_file("good.bin", &good), xml_file("bad.bin", &bad)];
+        let served = vec![("good.bin", good.clone()), ("bad.bin", test_data(2001).split_off(1))];
+        let outcome = download_test_item(&dir, files, served, &[]).await.unwrap();
+        assert_eq!(outcome.failed_files, ["bad.bin"]);
+        assert_eq!(fs::read(dir.join("good.bin")).unwrap(), good);
+        assert!(!part_path(&dir.join("good.bin")).exists());
+        assert!(!dir.join("bad.bin").exists());
+        assert!(!part_path(&dir.join("bad.bin")).exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn min_free_space_stops_before_downloading() {
+        let dir = test_dir("min-free-space");
+        let data = test_data(1000);
+        let served = vec![("data.bin", data.clone())];
+        let error = download_test_item(&dir, vec![xml_file("data.bin", &data)], served.clone(), &["--min-free-space", "1000000TiB"])
+            .await
+            .unwrap_err();
+        assert!(matches!(error.downcast_ref::<IaGetError>(), Some(IaGetError::InsufficientSpace { .. })), "{}", error);
+        assert_eq!(exit_code(&*error), EXIT_NO_SPACE);
+        assert!(!dir.join("data.bin").exists());
+
+        download_test_item(&dir, vec![xml_file("data.bin", &data)], served, &["--min-free-space", "1K"]).await.unwrap();
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn event_log_records_each_stage_of_a_download() {
+        let dir = test_dir("event-log");
+        let log = dir.join("events.jsonl");
+        fs::write(&log, "{\"event\":\"earlier run\"}\n").unwrap();
+        let (good, bad) = (test_data(3000), test_data(2000));
+        let files = vec![xml_file("good.bin", &good), xml_file("bad.bin", &bad)];
+        let served = vec![("good.bin", good), ("bad.bin", test_data(2001).split_off(1))];
+        download_test_item(&dir, files, served, &["--event-log", log.to_str().unwrap()]).await.unwrap();
+        let records: Vec<serde_json::Value> =
+            fs::read_to_string(&log).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let events: Vec<&str> = records.iter().map(|record| record["event"].as_str().unwrap()).collect();
+        assert_eq!(events, ["earlier run", "download_started", "file_completed", "file_failed", "download_finished"]);
+        assert_eq!(records[1]["files"], 2);
+        assert_eq!(records[1]["bytes"], 5000);
+        assert_eq!(records[2]["name"], "good.bin");
+        assert_eq!(records[3]["name"], "bad.bin");
+        assert_eq!(records[4]["failed"], 1);
+        assert!(records[1..].iter().all(|record| record["identifier"] == "item" && record["timestamp"].is_f64()));
+
+        download_test_item(&dir, Vec::new(), Vec::new(), &["--event-log", log.to_str().unwrap(), "--truncate-event-log"]).await.unwrap_err();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "");
+    }
+
+    #[test]
+    fn identifier_lists_skip_blank_lines_and_comments() {
+        let text = "# wanted items\r\nfirst-item\r\n\r\n  https://archive.org/details/second-item  \n#skipped\n\tthird_item\n";
+        let entries = list_entries(text);
+        assert_eq!(entries, ["first-item", "https://archive.org/details/second-item", "third_item"]);
+        let identifiers: Vec<String> = entries.iter().map(|entry| normalize_archive_identifier(entry).unwrap()).collect();
+        assert_eq!(identifiers, ["first-item", "second-item", "third_item"]);
+        assert!(list_entries("\n# nothing here\n").is_empty());
+    }
+
+    #[test]
+    fn dark_and_restricted_items_are_recognised() {
+        let restriction = |raw: serde_json::Value| item_restriction(&raw);
+        assert!(matches!(restriction(serde_json::json!({ "is_dark": true })), Some(IaGetError::Restricted { dark: true })));
+        assert!(matches!(
+            restriction(serde_json::json!({ "metadata": { "access-restricted-item": "true" } })),
+            Some(IaGetError::Restricted { dark: false })
+        ));
+        assert!(restriction(serde_json::json!({ "is_dark": false, "metadata": { "access-restricted-item": "false" } })).is_none());
+        assert!(restriction(serde_json::json!({ "metadata": { "title": "Open" } })).is_none());
+
+        let error: Box<dyn Error> = IaGetError::Restricted { dark: true }.into();
+        assert_eq!(exit_code(&*error), EXIT_RESTRICTED);
+        assert_eq!(error_kind(&*error), "restricted");
+        assert!(error.to_string().contains("dark"));
+    }
+
+    #[test]
+    fn collections_are_read_from_item_metadata() {
+        let collections = |raw: serde_json::Value| serde_json::from_value::<ItemMetadata>(raw).unwrap().metadata.collection;
+        assert_eq!(collections(serde_json::json!({ "metadata": { "collection": ["etree", "stream_only"] } })), ["etree", "stream_only"]);
+        assert_eq!(collections(serde_json::json!({ "metadata": { "collection": "opensource" } })), ["opensource"]);
+        assert!(collections(serde_json::json!({ "metadata": {} })).is_empty());
+        assert!(collections(serde_json::json!({})).is_empty());
+
+        assert!(app().try_get_matches_from(["ia-get", "item", "--list-collections"]).is_ok());
+        let conflict = app().try_get_matches_from(["ia-get", "item", "--list-collections", "--collection", "opensource"]).unwrap_err();
+        assert_eq!(conflict.kind(), clap::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn checksum_files_are_parsed_like_coreutils_output() {
+        let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+        let sha1 = "DA39A3EE5E6B4B0D3255BFEF95601890AFD80709";
+        let text = format!("# made by md5sum\n{}  plain.txt\n{} *./binary.iso\n\nnot-hex  skipped.txt\n{}\n", md5, sha1, md5);
+        let checksums = parse_checksum_file(&text, "disc1/");
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(checksums["disc1/plain.txt"].algorithm, HashAlgorithm::Md5);
+        assert_eq!(checksums["disc1/binary.iso"].algorithm, HashAlgorithm::Sha1);
+        assert_eq!(checksums["disc1/binary.iso"].hex, sha1.to_ascii_lowercase());
+        assert!(is_checksum_file("disc1/SHA256SUMS.txt"));
+        assert!(is_checksum_file("md5sums"));
+        assert!(!is_checksum_file("md5sums.json"));
+    }
+
+    #[tokio::test]
+    async fn checksum_source_verifies_files_without_metadata_checksums() {
+        use sha2::Digest;
+
+        let dir = test_dir("checksum-source");
+        let (good, bad) = (test_data(3000), test_data(2000));
+        let sums = format!("{:x}  good.bin\n{:x}  bad.bin\n", sha2::Sha256::digest(&good), sha2::Sha256::digest(&bad));
+        let unlisted = |name: &str, data: &[u8]| XmlFile { md5: None, ..xml_file(name, data) };
+        let files = vec![unlisted("good.bin", &good), unlisted("bad.bin", &bad), xml_file("SHA256SUMS", sums.as_bytes())];
+        let served = vec![("good.bin", good), ("bad.bin", test_data(2001).split_off(1)), ("SHA256SUMS", sums.into_bytes())];
+        let outcome = download_test_item(&dir, files, served, &["--checksum-source", "auto"]).await.unwrap();
+        assert_eq!(outcome.failed_files, ["bad.bin"]);
+        assert!(outcome.unverified_files.is_empty(), "{:?}", outcome.unverified_files);
+    }
+
+    #[test]
+    fn hashing_reads_through_the_hash_buffer() {
+        /// Counts the largest piece of the file handed over at once.
+        struct Largest(usize, md5::Context);
+
+        impl Write for Largest {
+            fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+                self.0 = self.0.max(data.len());
+                self.1.consume(data);
+                Ok(data.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let path = test_dir("hash-buffer").join("large.bin");
+        let data = test_data(3_000_000);
+        fs::write(&path, &data).unwrap();
+        let mut hasher = Largest(0, md5::Context::new());
+        hash_file(&path, 4096, &mut hasher).unwrap();
+        assert_eq!(hasher.0, 4096);
+        assert_eq!(hasher.1.compute(), md5::compute(&data));
+    }
+
+    #[tokio::test]
+    async fn unreachable_mirror_falls_back_to_the_download_url() {
+        let data = test_data(3000);
+        let served = data.clone();
+        let base = mock_server(move |_, path, range| match path {
+            "/download/item/data.bin" => MockResponse::file(&served, range, true),
+            _ => MockResponse::status(404),
+        })
+        .await;
+        // Nothing listens on port 1, so every request to the mirror is refused
+        let raw_metadata = serde_json::json!({ "dir": "/1/items/item", "workable_servers": ["127.0.0.1:1"] });
+        for (force, kept) in [(false, true), (true, false)] {
+            let dir = test_dir("mirror-fallback");
+            let prepared = PreparedItem {
+                identifier: "item".to_string(),
+                xml_url: format!("{}download/item/item_files.xml", base),
+                item_url: base.join("download/item/").unwrap(),
+                files: XmlFiles { files: vec![xml_file("data.bin", &data)] },
+                raw_metadata: Some(raw_metadata.clone()),
+            };
+            let mut args = vec!["item", "--output-template", dir.to_str().unwrap(), "--server", "127.0.0.1:1", "--retries", "0"];
+            if force {
+                args.push("--force-server");
+            }
+            let result = with_context(&args, async |ctx| download_prepared(Vec::new(), Ok(prepared), None, ctx).await).await;
+            assert_eq!(result.is_ok(), kept, "--force-server {}", force);
+            assert_eq!(fs::read(dir.join("data.bin")).ok(), kept.then(|| data.clone()), "--force-server {}", force);
+        }
+    }
+
+    #[tokio::test]
+    async fn item_zip_is_only_used_for_every_file() {
+        let dir = test_dir("as-zip");
+        let (kept, skipped) = (test_data(3000), test_data(2000));
+        let files = vec![xml_file("kept.bin", &kept), xml_file("skipped.txt", &skipped)];
+        let served = vec![("kept.bin", kept.clone()), ("skipped.txt", skipped)];
+        // A filtered selection is downloaded file by file, the zip would hold too much
+        let outcome = download_test_item(&dir, files, served, &["--as-zip", "--include-ext", "bin"]).await.unwrap();
+        assert!(outcome.failed_files.is_empty());
+        assert_eq!(fs::read(dir.join("kept.bin")).unwrap(), kept);
+        assert!(!dir.join("skipped.txt").exists());
+        assert!(!dir.join("item.zip").exists());
+    }
+
+    #[tokio::test]
+    async fn missing_item_zip_falls_back_to_files() {
+        let dir = test_dir("as-zip-missing");
+        let data = test_data(3000);
+        let served = vec![("file.bin", data.clone())];
+        // The mock server answers 404 for /compress/item
+        let outcome = download_test_item(&dir, vec![xml_file("file.bin", &data)], served, &["--as-zip"]).await.unwrap();
+        assert!(outcome.failed_files.is_empty());
+        assert_eq!(fs::read(dir.join("file.bin")).unwrap(), data);
+        assert!(!dir.join("item.zip").exists());
+        assert!(!part_path(&dir.join("item.zip")).exists());
+
+        let base = mock_server(|_, _, _| MockResponse::status(404)).await;
+        let item_url = base.join("download/item/").unwrap();
+        let error = with_context(&["item"], async |ctx| download_item_zip(ctx, &item_url, "item", &dir).await)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("HTTP 404"), "{}", error);
+    }
+
+    #[test]
+    fn transfer_timeout_grows_with_file_size() {
+        let config = |args: &[&str]| ClientConfig::from_matches(&app().get_matches_from([&["ia-get", "item"], args].concat()));
+        let scaled = config(&["--timeout", "30", "--min-expected-rate", "1MiB"]);
+        assert_eq!(scaled.transfer_timeout(10 * 1024), Some(Duration::from_secs(30) + Duration::from_secs_f64(0.009765625)));
+        assert_eq!(scaled.transfer_timeout(10 << 30), Some(Duration::from_secs(30 + 10 * 1024)));
+        assert!(scaled.transfer_timeout(10 << 30) > scaled.transfer_timeout(10 * 1024));
+        // Without a rate only the read timeout limits a download
+        assert_eq!(config(&["--timeout", "30"]).transfer_timeout(10 << 30), None);
+    }
+
+    #[test]
+    fn metadata_fields_accept_strings_numbers_and_lists() {
+        let fields: ItemFields = serde_json::from_value(serde_json::json!({
+            "title": "One title",
+            "creator": ["First", null, "Second", { "nested": true }, ["deeper"]],
+            "date": 1999,
+            "subject": [true, 2.5],
+            "description": null,
+        }))
+        .unwrap();
+        assert_eq!(fields.title, ["One title"]);
+        assert_eq!(fields.creator, ["First", "Second"]);
+        assert_eq!(fields.date, ["1999"]);
+        assert_eq!(fields.subject, ["true", "2.5"]);
+        assert!(fields.description.is_empty());
+        assert!(fields.collection.is_empty());
+    }
+
+    /// Serve `data` for `data.bin`, corrupted the first `corrupted` times it is asked for.
+    async fn corrupting_server(data: Vec<u8>, corrupted: usize) -> reqwest::Url {
+        let requests = std::sync::atomic::AtomicUsize::new(0);
+        mock_server(move |method, path, range| match path {
+            "/download/item/data.bin" if method == "GET" && requests.fetch_add(1, Ordering::Relaxed) < corrupted => {
+                MockResponse::file(&data.iter().map(|byte| byte ^ 0xff).collect::<Vec<u8>>(), range, true)
+            }
+            "/download/item/data.bin" => MockResponse::file(&data, range, true),
+            _ => MockResponse::status(404),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn failures_list_retries_only_the_failed_files() {
+        let (data, other) = (test_data(3000), test_data(2000));
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (served, seen) = ((data.clone(), other.clone()), requests.clone());
+        let corrupted = std::sync::atomic::AtomicBool::new(true);
+        let base = mock_server(move |_, path, range| {
+            seen.lock().unwrap().push(path.to_string());
+            match path {
+                // Corrupted the first time only
+                "/download/item/data.bin" if corrupted.swap(false, Ordering::Relaxed) => {
+                    MockResponse::file(&served.0.iter().map(|byte| byte ^ 0xff).collect::<Vec<u8>>(), range, true)
+                }
+                "/download/item/data.bin" => MockResponse::file(&served.0, range, true),
+                "/download/item/other.bin" => MockResponse::file(&served.1, range, true),
+                _ => MockResponse::status(404),
+            }
+        })
+        .await;
+        let dir = test_dir("failures-out");
+        let failures = dir.join("failures.json");
+        let prepared = || PreparedItem {
+            identifier: "item".to_string(),
+            xml_url: format!("{}download/item/item_files.xml", base),
+            item_url: base.join("download/item/").unwrap(),
+            files: XmlFiles { files: vec![xml_file("data.bin", &data), xml_file("other.bin", &other)] },
+            raw_metadata: None,
+        };
+        let output = dir.join("item");
+        let args = ["item", "--output-template", output.to_str().unwrap(), "--failures-out", failures.to_str().unwrap()];
+        with_context(&args, async |ctx| {
+            let outcome = download_prepared(Vec::new(), Ok(prepared()), None, ctx).await.unwrap();
+            assert_eq!(outcome.failed_files, ["data.bin"]);
+            ctx.write_failures();
+        })
+        .await;
+        let listed: Vec<FailedFile> = serde_json::from_str(&fs::read_to_string(&failures).unwrap()).unwrap();
+        let listed: Vec<(&str, &str, &str)> =
+            listed.iter().map(|failure| (failure.identifier.as_str(), failure.name.as_str(), failure.kind.as_str())).collect();
+        assert_eq!(listed, [("item", "data.bin", "checksum_mismatch")]);
+
+        requests.lock().unwrap().clear();
+        let args = ["--output-template", output.to_str().unwrap(), "--retry-file", failures.to_str().unwrap()];
+        let outcome = with_context(&args, async |ctx| download_prepared(Vec::new(), Ok(prepared()), None, ctx).await).await.unwrap();
+        assert!(outcome.failed_files.is_empty());
+        assert_eq!(fs::read(output.join("data.bin")).unwrap(), data);
+        let requests = requests.lock().unwrap();
+        assert!(!requests.is_empty() && requests.iter().all(|path| path == "/download/item/data.bin"), "{:?}", requests);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatches_are_downloaded_again() {
+        let data = test_data(3000);
+        let dir = test_dir("retry-on-mismatch");
+        let base = corrupting_server(data.clone(), 2).await;
+        let outcome = download_from(base, &dir, vec![xml_file("data.bin", &data)], &["--retry-on-mismatch", "2"]).await.unwrap();
+        assert!(outcome.failed_files.is_empty(), "{:?}", outcome.failed_files);
+        assert_eq!(fs::read(dir.join("data.bin")).unwrap(), data);
+
+        let dir = test_dir("retry-on-mismatch-exhausted");
+        let base = corrupting_server(data.clone(), 2).await;
+        let outcome = download_from(base, &dir, vec![xml_file("data.bin", &data)], &["--retry-on-mismatch", "1"]).await.unwrap();
+        assert_eq!(outcome.failed_files, ["data.bin"]);
+        assert!(!dir.join("data.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn explanations_name_the_filter_that_decided() {
+        let _units = UNITS.lock().await;
+        let files = vec![
+            xml_file("movie.mp4", &test_data(5000)),
+            xml_file("movie.srt", &test_data(100)),
+            xml_file("notes.txt", &test_data(5000)),
+            xml_file("small.mp4", &test_data(10)),
+            xml_file("extra.mp4", &test_data(5000)),
+            xml_file("thumbs.db", &test_data(5000)),
+        ];
+        let args = ["item", "--include-ext", "mp4", "--exclude-ext", "db", "--min-size", "1K", "--with-sidecars", "--sidecar-ext", "srt"];
+        with_context(&args, async |ctx| {
+            let explain = |name| {
+                let explanation = explain_selection(&files, &ctx.filters, None, Some(2), name);
+                (explanation.selected, explanation.reason)
+            };
+            assert_eq!(explain("movie.mp4"), (true, "included".to_string()));
+            assert_eq!(explain("movie.srt"), (true, "included by --with-sidecars, although 100 B is below --min-size 1.00 KiB".to_string()));
+            assert_eq!(explain("notes.txt"), (false, "matches none of --include-ext mp4".to_string()));
+            assert_eq!(explain("small.mp4"), (false, "10 B is below --min-size 1.00 KiB".to_string()));
+            assert_eq!(explain("thumbs.db"), (false, "excluded by --exclude-ext db".to_string()));
+            assert_eq!(explain("extra.mp4"), (false, "file 3 of the selection is beyond --max-files 2".to_string()));
+            assert_eq!(explain("missing.mp4"), (false, "not in the item's file list".to_string()));
+
+            // Sorted by name the selection is extra.mp4, movie.mp4, movie.srt
+            let explain_range = |name| {
+                let explanation = explain_selection(&files, &ctx.filters, Some((1, None)), Some(1), name);
+                (explanation.selected, explanation.reason)
+            };
+            assert_eq!(explain_range("extra.mp4"), (false, "index 0 of the selection sorted by name is outside --range 1:".to_string()));
+            assert_eq!(explain_range("movie.mp4"), (true, "included".to_string()));
+            assert_eq!(explain_range("movie.srt"), (false, "file 2 of the selection is beyond --max-files 1".to_string()));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn hash_errors_are_logged_once_per_file() {
+        let data = test_data(3000);
+        let dir = test_dir("log-hash-errors");
+        let log = dir.join("hash-errors.jsonl");
+        let base = corrupting_server(data.clone(), 3).await;
+        let args = ["--retry-on-mismatch", "2", "--log-hash-errors", log.to_str().unwrap()];
+        let outcome = download_from(base.clone(), &dir, vec![xml_file("data.bin", &data)], &args).await.unwrap();
+        assert_eq!(outcome.failed_files, ["data.bin"]);
+        let records: Vec<serde_json::Value> =
+            fs::read_to_string(&log).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        let corrupted: Vec<u8> = data.iter().map(|byte| byte ^ 0xff).collect();
+        assert_eq!(records[0]["identifier"], "item");
+        assert_eq!(records[0]["name"], "data.bin");
+        assert_eq!(records[0]["expected"], format!("{:x}", md5::compute(&data)));
+        assert_eq!(records[0]["actual"], format!("{:x}", md5::compute(&corrupted)));
+        assert_eq!(records[0]["size"], 3000);
+        assert_eq!(records[0]["url"], format!("{}download/item/data.bin", base));
+    }
+
+    #[test]
+    fn ranges_parse_with_open_ends() {
+        assert_eq!(parse_range("2:5"), Ok((2, Some(5))));
+        assert_eq!(parse_range(":10"), Ok((0, Some(10))));
+        assert_eq!(parse_range(" 3 : "), Ok((3, None)));
+        assert_eq!(parse_range(":"), Ok((0, None)));
+        assert!(parse_range("5").is_err());
+        assert!(parse_range("5:2").is_err());
+        assert!(parse_range("a:2").is_err());
+    }
+
+    #[tokio::test]
+    async fn complementary_ranges_share_out_every_file() {
+        let names = ["c.bin", "a.bin", "e.bin", "b.bin", "d.bin"];
+        let contents: Vec<(&str, Vec<u8>)> = names.iter().enumerate().map(|(index, name)| (*name, test_data(100 + index))).collect();
+        let mut shards = Vec::new();
+        for range in [":2", "2:4", "4:"] {
+            let dir = test_dir(&format!("range-{}", range.replace(':', "-")));
+            let files = contents.iter().map(|(name, data)| xml_file(name, data)).collect();
+            download_test_item(&dir, files, contents.clone(), &["--range", range]).await.unwrap();
+            let mut downloaded: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+            downloaded.sort();
+            shards.push(downloaded);
+        }
+        assert_eq!(shards, [vec!["a.bin", "b.bin"], vec!["c.bin", "d.bin"], vec!["e.bin"]]);
+    }
+
+    #[test]
+    fn write_failures_get_their_own_errors() {
+        use std::io::ErrorKind;
+
+        let path = Path::new("item/file.bin.part");
+        let cases = [
+            (ErrorKind::StorageFull, EXIT_NO_SPACE, "disk_full"),
+            (ErrorKind::QuotaExceeded, EXIT_NO_SPACE, "disk_full"),
+            (ErrorKind::PermissionDenied, EXIT_PERMISSION_DENIED, "permission_denied"),
+            (ErrorKind::ReadOnlyFilesystem, EXIT_PERMISSION_DENIED, "permission_denied"),
+            (ErrorKind::InvalidFilename, EXIT_PATH_TOO_LONG, "path_too_long"),
+            (ErrorKind::UnexpectedEof, EXIT_FAILURE, "io"),
+        ];
+        for (kind, code, name) in cases {
+            let error = file_system_error(std::io::Error::from(kind), path);
+            assert_eq!((exit_code(&*error), error_kind(&*error)), (code, name), "{:?}", kind);
+            if code != EXIT_FAILURE {
+                assert!(error.to_string().contains("item/file.bin.part"), "{}", error);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pattern_files_mix_extensions_and_globs() {
+        let dir = test_dir("pattern-files");
+        let include = dir.join("include.txt");
+        fs::write(&include, "# formats to keep\n.FLAC\nmp3\n\ncovers/*.jpg\nbooklet?.pdf\n").unwrap();
+        let (extensions, patterns) = read_pattern_file(include.to_str().unwrap()).unwrap();
+        assert_eq!(extensions, ["flac", "mp3"]);
+        let patterns: Vec<&str> = patterns.iter().map(glob::Pattern::as_str).collect();
+        assert_eq!(patterns, ["covers/*.jpg", "booklet?.pdf"]);
+
+        let exclude = dir.join("exclude.txt");
+        fs::write(&exclude, "*_sample.*\n").unwrap();
+        let names = ["a.flac", "b.MP3", "covers/front.JPG", "booklet1.pdf", "booklet10.pdf", "c_sample.flac", "d.ogg"];
+        let args = ["--include-from", include.to_str().unwrap(), "--include-ext", "ogg", "--exclude-from", exclude.to_str().unwrap()];
+        assert_eq!(filtered_names(&names, &args).await, ["a.flac", "b.MP3", "covers/front.JPG", "booklet1.pdf", "d.ogg"]);
+
+        fs::write(&include, "[broken\n").unwrap();
+        assert!(read_pattern_file(include.to_str().unwrap()).unwrap_err().contains("[broken"));
+    }
+
+    #[tokio::test]
+    async fn preflight_sizes_let_size_filters_apply() {
+        let (big, small, notes) = (test_data(3000), test_data(500), test_data(4000));
+        let patterns = test_dir("preflight-patterns").join("include.txt");
+        fs::write(&patterns, "bin\n").unwrap();
+        // A file of unknown size cannot be ruled out by --min-size until a HEAD request finds it
+        for (preflight, downloaded) in [(false, vec!["big.bin", "small.bin"]), (true, vec!["big.bin"])] {
+            let dir = test_dir("preflight-sizes");
+            let without_size = |name: &str, data: &[u8]| XmlFile { size: None, ..xml_file(name, data) };
+            let files = vec![without_size("big.bin", &big), without_size("small.bin", &small), xml_file("notes.txt", &notes)];
+            let served = vec![("big.bin", big.clone()), ("small.bin", small.clone()), ("notes.txt", notes.clone())];
+            let mut args = vec!["--min-size", "1000", "--include-from", patterns.to_str().unwrap()];
+            if preflight {
+                args.push("--preflight-sizes");
+            }
+            let outcome = download_test_item(&dir, files, served, &args).await.unwrap();
+            assert!(outcome.failed_files.is_empty());
+            let mut present: Vec<String> =
+                fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+            present.sort();
+            assert_eq!(present, downloaded, "--preflight-sizes {}", preflight);
+        }
+    }
+
+    #[tokio::test]
+    async fn formats_are_inferred_from_extensions() {
+        let inferred = |name: &str| xml_file(name, &[]).format_or_inferred().map(str::to_string);
+        assert_eq!(inferred("Cover.JPG").as_deref(), Some("JPEG"));
+        assert_eq!(inferred("backup.tar.gz").as_deref(), Some("GZIP"));
+        assert_eq!(inferred("README"), None);
+        assert_eq!(inferred("v1.2/notes"), None);
+        assert_eq!(inferred("data.unknown"), None);
+        let listed = XmlFile { format: Some("JPEG Thumb".to_string()), ..xml_file("cover_thumb.jpg", &[]) };
+        assert_eq!(listed.format_or_inferred(), Some("JPEG Thumb"));
+
+        let names = ["Cover.JPG", "track.flac", "notes.txt"];
+        assert_eq!(filtered_names(&names, &["--include-formats", "JPEG,text"]).await, ["Cover.JPG", "notes.txt"]);
+    }
+
+    #[test]
+    fn mtimes_parse_and_clamp_far_future_times() {
+        assert_eq!(parse_mtime("1700000000"), Ok(1_700_000_000));
+        assert_eq!(parse_mtime(" 1700000000.999 "), Ok(1_700_000_000));
+        assert!(parse_mtime("yesterday").is_err());
+        assert!(parse_mtime("-5").is_err());
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(file_mtime("1600000000", now), Ok((UNIX_EPOCH + Duration::from_secs(1_600_000_000), None)));
+        // A little ahead of the local clock is tolerated
+        let soon = 1_700_000_000 + 60 * 60;
+        assert_eq!(file_mtime(&soon.to_string(), now), Ok((UNIX_EPOCH + Duration::from_secs(soon), None)));
+        let (clamped, warning) = file_mtime("1800000000", now).unwrap();
+        assert_eq!(clamped, now);
+        assert!(warning.unwrap().contains("clamped to now"));
+        assert!(file_mtime("garbage", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn preserved_mtimes_come_from_the_metadata() {
+        let dir = test_dir("preserve-mtime");
+        let data = test_data(1000);
+        let dated = |name: &str, mtime: &str| XmlFile { mtime: Some(mtime.to_string()), ..xml_file(name, &data) };
+        let files = vec![dated("old.bin", "1600000000"), dated("future.bin", "99999999999"), dated("bogus.bin", "soon")];
+        let served = vec![("old.bin", data.clone()), ("future.bin", data.clone()), ("bogus.bin", data.clone())];
+        let started = SystemTime::now();
+        download_test_item(&dir, files, served, &["--preserve-mtime"]).await.unwrap();
+        let modified = |name: &str| fs::metadata(dir.join(name)).unwrap().modified().unwrap();
+        assert_eq!(modified("old.bin"), UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert!(modified("future.bin") >= started && modified("future.bin") <= SystemTime::now());
+        assert!(modified("bogus.bin") >= started);
+    }
+
+    #[tokio::test]
+    async fn final_verify_reports_changed_and_missing_files() {
+        let dir = test_dir("final-verify");
+        let data = test_data(2000);
+        let md5 = format!("{:x}", md5::compute(&data));
+        fs::write(dir.join("same.bin"), &data).unwrap();
+        fs::write(dir.join("changed.bin"), test_data(1999)).unwrap();
+        let checks = ["same.bin", "changed.bin", "missing.bin"]
+            .iter()
+            .map(|name| (name.to_string(), dir.join(name), HashAlgorithm::Md5, md5.clone()))
+            .collect();
+        let mut failures = recheck_files(checks).await;
+        failures.sort();
+        let kinds: Vec<(&str, &str)> = failures.iter().map(|(name, kind, _)| (name.as_str(), *kind)).collect();
+        assert_eq!(kinds, [("changed.bin", "checksum_mismatch"), ("missing.bin", "missing_file")]);
+
+        let outcome = download_test_item(&dir, vec![xml_file("fresh.bin", &data)], vec![("fresh.bin", data.clone())], &["--final-verify"])
+            .await
+            .unwrap();
+        assert!(outcome.failed_files.is_empty(), "{:?}", outcome.failed_files);
+    }
+
+    #[test]
+    fn durations_take_an_optional_unit() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 30 M "), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+        assert!(app().try_get_matches_from(["ia-get", "item", "--max-duration", "99999999999999999d"]).is_err());
+        assert!(app().try_get_matches_from(["ia-get", "item", "--max-rate", "fast"]).is_err());
+    }
 }